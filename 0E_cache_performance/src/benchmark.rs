@@ -26,15 +26,23 @@ use crate::uart;
 use core::sync::atomic::{compiler_fence, Ordering};
 use cortex_a::{barrier, regs::*};
 
+/// Size of the smallest data cache line in the system, in bytes.
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    unsafe { asm!("mrs $0, CTR_EL0" : "=r"(ctr) ::: "volatile") };
+
+    // CTR_EL0.DminLine: log2 of the number of words in the smallest line.
+    4 << ((ctr >> 16) & 0xF)
+}
+
 /// We assume that addr is cacheline aligned
 fn batch_modify_time(addr: usize) -> Option<u64> {
-    const CACHELINE_SIZE_BYTES: usize = 64; // TODO: retrieve this from a system register
     const NUM_CACHELINES_TOUCHED: usize = 5;
     const NUM_BENCH_ITERATIONS: usize = 20_000;
 
-    const NUM_BYTES_TOUCHED: usize = CACHELINE_SIZE_BYTES * NUM_CACHELINES_TOUCHED;
+    let num_bytes_touched = dcache_line_size() * NUM_CACHELINES_TOUCHED;
 
-    let mem = unsafe { core::slice::from_raw_parts_mut(addr as *mut usize, NUM_BYTES_TOUCHED) };
+    let mem = unsafe { core::slice::from_raw_parts_mut(addr as *mut usize, num_bytes_touched) };
 
     // Benchmark starts here
     let t1 = CNTPCT_EL0.get();
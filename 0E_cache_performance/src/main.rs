@@ -24,6 +24,7 @@
 
 #![no_std]
 #![no_main]
+#![feature(asm)]
 #![feature(range_contains)]
 
 mod benchmark;
@@ -70,7 +70,11 @@ unsafe fn reset() -> ! {
 fn setup_and_enter_el1_from_el2() -> ! {
     use cortex_a::{asm, regs::*};
 
-    const STACK_START: u64 = 0x80_000;
+    // Top of core 0's stack and exception stack. They must match the kernel's
+    // stack layout, which puts a 4 KiB guard page below each 64 KiB stack
+    // slot, starting at address 0. See `memory::kernel_stack` of the kernel.
+    const STACK_START: u64 = 0x10_000;
+    const EXCEPTION_STACK_START: u64 = 0x50_000;
 
     // Enable timer counter registers for EL1
     CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
@@ -84,21 +88,25 @@ fn setup_and_enter_el1_from_el2() -> ! {
     // Set up a simulated exception return.
     //
     // First, fake a saved program status, where all interrupts were
-    // masked and SP_EL1 was used as a stack pointer.
+    // masked and SP_EL0 was used as a stack pointer. SP_EL1 is thereby
+    // reserved for exception entry.
     SPSR_EL2.write(
         SPSR_EL2::D::Masked
             + SPSR_EL2::A::Masked
             + SPSR_EL2::I::Masked
             + SPSR_EL2::F::Masked
-            + SPSR_EL2::M::EL1h,
+            + SPSR_EL2::M::EL1t,
     );
 
     // Second, let the link register point to reset().
     ELR_EL2.set(reset as *const () as u64);
 
-    // Set up SP_EL1 (stack pointer), which will be used by EL1 once
+    // Set up SP_EL0 (stack pointer), which will be used by EL1 once
     // we "return" to it.
-    SP_EL1.set(STACK_START);
+    SP_EL0.set(STACK_START);
+
+    // Set up SP_EL1, which will be used by EL1 when taking exceptions.
+    SP_EL1.set(EXCEPTION_STACK_START);
 
     // Use `eret` to "return" to EL1. This will result in execution of
     // `reset()` in EL1.
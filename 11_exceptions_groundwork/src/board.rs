@@ -0,0 +1,197 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Information about the board the kernel runs on, as reported by the
+//! firmware.
+
+use crate::devices::hw::videocore_mbox::{self, tag, PropertyMessage, VideocoreMbox};
+use crate::{println, sync::NullLock};
+
+static INFO: NullLock<Option<Info>> = NullLock::new(None);
+
+/// The new-style revision code.
+///
+/// Layout: `NOQu uuWu FMMM CCCC PPPP TTTT TTTT RRRR`, see
+/// https://www.raspberrypi.org/documentation/hardware/raspberrypi/revision-codes/README.md
+#[derive(Copy, Clone)]
+pub struct Revision(pub u32);
+
+impl Revision {
+    const NEW_STYLE: u32 = 1 << 23;
+
+    fn is_new_style(self) -> bool {
+        self.0 & Self::NEW_STYLE != 0
+    }
+
+    /// The board type, e.g. 0x8 for the 3B.
+    fn board_type(self) -> Option<u32> {
+        if self.is_new_style() {
+            Some((self.0 >> 4) & 0xFF)
+        } else {
+            None
+        }
+    }
+
+    pub fn model(self) -> &'static str {
+        match self.board_type() {
+            Some(0x0) => "A",
+            Some(0x1) => "B",
+            Some(0x2) => "A+",
+            Some(0x3) => "B+",
+            Some(0x4) => "2B",
+            Some(0x6) => "CM1",
+            Some(0x8) => "3B",
+            Some(0x9) => "Zero",
+            Some(0xA) => "CM3",
+            Some(0xC) => "Zero W",
+            Some(0xD) => "3B+",
+            Some(0xE) => "3A+",
+            Some(0x10) => "CM3+",
+            Some(0x11) => "4B",
+            _ => "unknown",
+        }
+    }
+
+    /// Revision of the PCB, e.g. 2 for 1.2.
+    pub fn pcb_revision(self) -> u32 {
+        self.0 & 0xF
+    }
+
+    /// Size of the SDRAM in MiB.
+    pub fn ram_size_mib(self) -> Option<u32> {
+        if self.is_new_style() {
+            Some(256 << ((self.0 >> 20) & 0x7))
+        } else {
+            None
+        }
+    }
+
+    pub fn manufacturer(self) -> &'static str {
+        if !self.is_new_style() {
+            return "unknown";
+        }
+
+        match (self.0 >> 16) & 0xF {
+            0 => "Sony UK",
+            1 => "Egoman",
+            2 | 4 => "Embest",
+            3 => "Sony Japan",
+            5 => "Stadium",
+            _ => "unknown",
+        }
+    }
+
+    /// On boards with a wireless module, the PL011 UART is wired to the
+    /// Bluetooth chip by the firmware.
+    pub fn has_bluetooth(self) -> bool {
+        match self.board_type() {
+            Some(0x8) | Some(0xC) | Some(0xD) | Some(0xE) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Info {
+    pub firmware: u32,
+    pub model: u32,
+    pub revision: Revision,
+    pub serial: u64,
+    pub mac: [u8; 6],
+    pub arm_memory: tag::MemoryRegion,
+    pub vc_memory: tag::MemoryRegion,
+}
+
+impl Info {
+    fn query(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<Info> {
+        let mut msg = PropertyMessage::new(v_mbox);
+        let firmware = msg.push(tag::GetFirmwareRevision)?;
+        let model = msg.push(tag::GetBoardModel)?;
+        let revision = msg.push(tag::GetBoardRevision)?;
+        let serial = msg.push(tag::GetSerial)?;
+        let mac = msg.push(tag::GetMacAddress)?;
+        let arm_memory = msg.push(tag::GetArmMemory)?;
+        let vc_memory = msg.push(tag::GetVcMemory)?;
+        msg.call()?;
+
+        Ok(Info {
+            firmware: msg.get(firmware)?,
+            model: msg.get(model)?,
+            revision: Revision(msg.get(revision)?),
+            serial: msg.get(serial)?,
+            mac: msg.get(mac)?,
+            arm_memory: msg.get(arm_memory)?,
+            vc_memory: msg.get(vc_memory)?,
+        })
+    }
+
+    pub fn print(&self) {
+        let r = self.revision;
+        let m = self.mac;
+
+        println!(
+            "[i] Board: Raspberry Pi {} Rev 1.{} (revision code {:#08X})",
+            r.model(),
+            r.pcb_revision(),
+            r.0
+        );
+        match r.ram_size_mib() {
+            Some(size) => println!(
+                "      RAM:        {} MiB, made by {}",
+                size,
+                r.manufacturer()
+            ),
+            None => println!("      RAM:        unknown"),
+        }
+        println!("      Firmware:   {:#010X}", self.firmware);
+        println!("      Model:      {:#010X}", self.model);
+        println!("      Serial:     {:016X}", self.serial);
+        println!(
+            "      MAC:        {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        );
+        println!(
+            "      ARM memory: {:#010X}, {:#010X} bytes",
+            self.arm_memory.base, self.arm_memory.size
+        );
+        println!(
+            "      VC memory:  {:#010X}, {:#010X} bytes",
+            self.vc_memory.base, self.vc_memory.size
+        );
+    }
+}
+
+/// Query the firmware and store the result for `info()`.
+pub fn init(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<Info> {
+    let info = Info::query(v_mbox)?;
+
+    INFO.lock(|i| *i = Some(info));
+
+    Ok(info)
+}
+
+/// The board information, if `init()` succeeded.
+pub fn info() -> Option<Info> {
+    INFO.lock(|i| *i)
+}
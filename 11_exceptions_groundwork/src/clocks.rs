@@ -0,0 +1,258 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The clocks managed by the firmware.
+//!
+//! Drivers whose timing is derived from a clock can register a notifier,
+//! which is called whenever the rate of that clock is seen to change.
+
+use crate::devices::hw::videocore_mbox::{self, tag, VideocoreMbox};
+use crate::{println, sync::IrqSafeNullLock};
+
+const MAX_NOTIFIERS: usize = 4;
+
+#[derive(Copy, Clone)]
+struct Notifier {
+    clock: Clock,
+    func: fn(usize, u32),
+    context: usize,
+
+    /// The rate the driver was last told about.
+    hz: u32,
+}
+
+static NOTIFIERS: IrqSafeNullLock<[Option<Notifier>; MAX_NOTIFIERS]> =
+    IrqSafeNullLock::new([None; MAX_NOTIFIERS]);
+
+/// The firmware clock IDs.
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+}
+
+impl Clock {
+    pub const ALL: [Clock; 10] = [
+        Clock::Emmc,
+        Clock::Uart,
+        Clock::Arm,
+        Clock::Core,
+        Clock::V3d,
+        Clock::H264,
+        Clock::Isp,
+        Clock::Sdram,
+        Clock::Pixel,
+        Clock::Pwm,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Clock::Emmc => "EMMC",
+            Clock::Uart => "UART",
+            Clock::Arm => "ARM",
+            Clock::Core => "CORE",
+            Clock::V3d => "V3D",
+            Clock::H264 => "H264",
+            Clock::Isp => "ISP",
+            Clock::Sdram => "SDRAM",
+            Clock::Pixel => "PIXEL",
+            Clock::Pwm => "PWM",
+        }
+    }
+}
+
+/// Whether the clock exists and is running.
+pub fn is_on(v_mbox: &mut VideocoreMbox, clock: Clock) -> videocore_mbox::Result<bool> {
+    let state = v_mbox.query(tag::GetClockState {
+        clock: clock as u32,
+    })?;
+
+    Ok(state == 0b01)
+}
+
+/// The current rate in Hz.
+pub fn rate(v_mbox: &mut VideocoreMbox, clock: Clock) -> videocore_mbox::Result<u32> {
+    v_mbox.query(tag::GetClockRate {
+        clock: clock as u32,
+    })
+}
+
+/// The highest rate the clock may be set to.
+pub fn max_rate(v_mbox: &mut VideocoreMbox, clock: Clock) -> videocore_mbox::Result<u32> {
+    v_mbox.query(tag::GetMaxClockRate {
+        clock: clock as u32,
+    })
+}
+
+/// The lowest rate the clock may be set to.
+pub fn min_rate(v_mbox: &mut VideocoreMbox, clock: Clock) -> videocore_mbox::Result<u32> {
+    v_mbox.query(tag::GetMinClockRate {
+        clock: clock as u32,
+    })
+}
+
+/// Set the rate in Hz. Returns the rate the firmware actually chose, which
+/// may be clamped to the min and max rates.
+///
+/// Setting the ARM clock also adjusts the voltage and the other turbo clocks,
+/// unless `skip_turbo` is set.
+pub fn set_rate(
+    v_mbox: &mut VideocoreMbox,
+    clock: Clock,
+    hz: u32,
+    skip_turbo: bool,
+) -> videocore_mbox::Result<u32> {
+    let hz = v_mbox.query(tag::SetClockRate {
+        clock: clock as u32,
+        hz,
+        skip_turbo,
+    })?;
+
+    // Other clocks may have followed along.
+    refresh(v_mbox);
+
+    Ok(hz)
+}
+
+/// Whether turbo mode is on.
+pub fn turbo(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<bool> {
+    v_mbox.query(tag::GetTurbo)
+}
+
+/// Switch turbo mode, which runs the ARM, V3D, H264 and ISP clocks at their
+/// max rates.
+pub fn set_turbo(v_mbox: &mut VideocoreMbox, on: bool) -> videocore_mbox::Result<()> {
+    v_mbox.query(tag::SetTurbo { on })?;
+    refresh(v_mbox);
+
+    Ok(())
+}
+
+/// Call `func` with `context` and the new rate whenever the rate of `clock`
+/// changes. `hz` is the rate the caller is currently set up for.
+///
+/// Returns an ID for `remove_notifier()`.
+pub fn add_notifier(
+    clock: Clock,
+    func: fn(usize, u32),
+    context: usize,
+    hz: u32,
+) -> Result<usize, &'static str> {
+    NOTIFIERS.lock(|n| {
+        let id = n
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or("No free clock notifier slot.")?;
+
+        n[id] = Some(Notifier {
+            clock,
+            func,
+            context,
+            hz,
+        });
+
+        Ok(id)
+    })
+}
+
+pub fn remove_notifier(id: usize) {
+    NOTIFIERS.lock(|n| {
+        if let Some(slot) = n.get_mut(id) {
+            *slot = None;
+        }
+    });
+}
+
+/// Query the clocks that have notifiers and call those whose rate changed.
+///
+/// Called after every change made through this module. The firmware also
+/// changes clocks on its own, e.g. when throttling, so call this
+/// periodically as well.
+pub fn refresh(v_mbox: &mut VideocoreMbox) {
+    for id in 0..MAX_NOTIFIERS {
+        let notifier = match NOTIFIERS.lock(|n| n[id]) {
+            Some(notifier) => notifier,
+            None => continue,
+        };
+
+        let hz = match rate(v_mbox, notifier.clock) {
+            Ok(hz) if hz != notifier.hz => hz,
+            _ => continue,
+        };
+
+        NOTIFIERS.lock(|n| {
+            if let Some(n) = n[id].as_mut() {
+                n.hz = hz;
+            }
+        });
+        (notifier.func)(notifier.context, hz);
+    }
+}
+
+/// Print the rates of all clocks.
+pub fn print(v_mbox: &mut VideocoreMbox) {
+    println!("[i] Clocks:");
+
+    for clock in Clock::ALL.iter() {
+        if let Err(e) = print_clock(v_mbox, *clock) {
+            println!("      {:<5} query failed: {:?}", clock.name(), e);
+        }
+    }
+
+    match turbo(v_mbox) {
+        Ok(on) => println!("      Turbo {}", if on { "on" } else { "off" }),
+        Err(e) => println!("      Turbo query failed: {:?}", e),
+    }
+}
+
+fn print_clock(v_mbox: &mut VideocoreMbox, clock: Clock) -> videocore_mbox::Result<()> {
+    if !is_on(v_mbox, clock)? {
+        println!("      {:<5} off", clock.name());
+        return Ok(());
+    }
+
+    // Query everything first, so that a failure does not leave half a line.
+    let rate = rate(v_mbox, clock)?;
+    let min_rate = min_rate(v_mbox, clock)?;
+    let max_rate = max_rate(v_mbox, clock)?;
+
+    println!(
+        "      {:<5} {:>4} MHz (min {:>4} MHz, max {:>4} MHz)",
+        clock.name(),
+        rate / 1_000_000,
+        min_rate / 1_000_000,
+        max_rate / 1_000_000
+    );
+
+    Ok(())
+}
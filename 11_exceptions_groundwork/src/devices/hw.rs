@@ -22,12 +22,28 @@
  * SOFTWARE.
  */
 
+mod dma;
+mod framebuffer;
 mod gpio;
+mod interrupt_controller;
 mod mini_uart;
 mod pl011_uart;
-mod videocore_mbox;
+mod power;
+mod rng;
+mod serial_config;
+mod system_timer;
+pub mod videocore_mbox;
+mod watchdog;
 
+pub use dma::{dma_memcpy, dma_memset, Chain, ControlBlock, Dma, DmaError};
+pub use framebuffer::{Color, Framebuffer, FramebufferError};
 pub use gpio::GPIO;
+pub use interrupt_controller::InterruptController;
 pub use mini_uart::MiniUart;
 pub use pl011_uart::PL011Uart;
+pub use power::{Partition, Power};
+pub use rng::Rng;
+pub use serial_config::{DataBits, FlowControl, FlowControlPins, Parity, SerialConfig, StopBits};
+pub use system_timer::SystemTimer;
 pub use videocore_mbox::VideocoreMbox;
+pub use watchdog::Watchdog;
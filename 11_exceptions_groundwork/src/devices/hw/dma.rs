@@ -0,0 +1,660 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::memory::{
+    bus, cache,
+    dma::{DmaBox, DmaSlice, Zeroable},
+};
+use crate::{idle, interrupt};
+use core::ops;
+use register::{mmio::*, register_bitfields};
+
+// The DMA controller. Channels 0-6 are full channels, channels 7-14 are
+// "lite" channels with a smaller maximum transfer length and without 2D
+// mode.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+register_bitfields! {
+    u32,
+
+    /// Control and Status
+    CS [
+        /// Write 1 to reset the channel.
+        RESET OFFSET(31) NUMBITS(1) [],
+
+        /// Write 1 to abort the current control block.
+        ABORT OFFSET(30) NUMBITS(1) [],
+
+        /// Wait until all AXI writes of a transfer have been acknowledged
+        /// before signaling its end.
+        WAIT_FOR_OUTSTANDING_WRITES OFFSET(28) NUMBITS(1) [],
+
+        /// AXI priority when the panic signal of the VideoCore is raised.
+        PANIC_PRIORITY OFFSET(20) NUMBITS(4) [],
+
+        /// AXI priority of normal transfers.
+        PRIORITY OFFSET(16) NUMBITS(4) [],
+
+        /// Set if an error was recorded in the DEBUG register.
+        ERROR OFFSET(8) NUMBITS(1) [],
+
+        /// Interrupt status. Write 1 to clear.
+        INT OFFSET(2) NUMBITS(1) [],
+
+        /// Set when the transfer of the whole chain is complete. Write 1 to
+        /// clear.
+        END OFFSET(1) NUMBITS(1) [],
+
+        /// Write 1 to start the chain at CONBLK_AD. Clears when the end of
+        /// the chain is reached.
+        ACTIVE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Transfer Information
+    TI [
+        /// Do not issue wide bursts.
+        NO_WIDE_BURSTS OFFSET(26) NUMBITS(1) [],
+
+        /// Peripheral whose DREQ paces the transfer.
+        PERMAP OFFSET(16) NUMBITS(5) [],
+
+        /// Number of beats per burst, minus one.
+        BURST_LENGTH OFFSET(12) NUMBITS(4) [],
+
+        /// Only read from the source if its DREQ is set.
+        SRC_DREQ OFFSET(10) NUMBITS(1) [],
+
+        /// 128 bit instead of 32 bit source reads.
+        SRC_WIDTH OFFSET(9) NUMBITS(1) [],
+
+        /// Increment the source address after each read.
+        SRC_INC OFFSET(8) NUMBITS(1) [],
+
+        /// Only write to the destination if its DREQ is set.
+        DEST_DREQ OFFSET(6) NUMBITS(1) [],
+
+        /// 128 bit instead of 32 bit destination writes.
+        DEST_WIDTH OFFSET(5) NUMBITS(1) [],
+
+        /// Increment the destination address after each write.
+        DEST_INC OFFSET(4) NUMBITS(1) [],
+
+        /// Wait for the write response of each write.
+        WAIT_RESP OFFSET(3) NUMBITS(1) [],
+
+        /// Interpret TXFR_LEN as YLENGTH rows of XLENGTH bytes, and apply
+        /// STRIDE after each row.
+        TDMODE OFFSET(1) NUMBITS(1) [],
+
+        /// Raise an interrupt when this control block is complete.
+        INTEN OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Debug
+    DEBUG [
+        /// Set for the lite channels.
+        LITE OFFSET(28) NUMBITS(1) [],
+
+        /// The AXI read of the transfer failed. Write 1 to clear.
+        READ_ERROR OFFSET(2) NUMBITS(1) [],
+
+        /// The read FIFO overflowed. Write 1 to clear.
+        FIFO_ERROR OFFSET(1) NUMBITS(1) [],
+
+        /// The AXI read last signal was not set when expected. Write 1 to
+        /// clear.
+        READ_LAST_NOT_SET_ERROR OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+/// Number of channels in the main register block. Channel 15 lives
+/// elsewhere and is used by the firmware.
+const NUM_CHANNELS: usize = 15;
+
+/// Channels that the firmware leaves to the ARM.
+const USABLE_CHANNELS: u16 = 0x7F35;
+
+/// Maximum length of a single control block.
+const MAX_LEN_FULL: usize = 0x3FFF_FFFF;
+const MAX_LEN_LITE: usize = 0xFFFF;
+
+/// Control blocks need to be 256 bit aligned.
+const CB_ALIGNMENT: usize = 32;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct ChannelRegisterBlock {
+    CS: ReadWrite<u32, CS::Register>,       // 0x00
+    CONBLK_AD: ReadWrite<u32>,              // 0x04
+    TI: ReadOnly<u32, TI::Register>,        // 0x08
+    SOURCE_AD: ReadOnly<u32>,               // 0x0C
+    DEST_AD: ReadOnly<u32>,                 // 0x10
+    TXFR_LEN: ReadOnly<u32>,                // 0x14
+    STRIDE: ReadOnly<u32>,                  // 0x18
+    NEXTCONBK: ReadWrite<u32>,              // 0x1C
+    DEBUG: ReadWrite<u32, DEBUG::Register>, // 0x20
+    __reserved: [u32; 55],                  // 0x24
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CHANNELS: [ChannelRegisterBlock; NUM_CHANNELS], // 0x000
+    __reserved_0: [u32; 56],                        // 0xF00
+    INT_STATUS: ReadWrite<u32>,                     // 0xFE0
+    __reserved_1: [u32; 3],                         // 0xFE4
+    ENABLE: ReadWrite<u32>,                         // 0xFF0
+}
+
+#[derive(Debug)]
+pub enum DmaError {
+    /// All channels are in use.
+    NoChannel,
+
+    /// The DMA heap is exhausted.
+    OutOfMemory,
+
+    /// No more room in the chain.
+    ChainFull,
+
+    /// A buffer is not visible to the DMA engine in one contiguous piece.
+    BadAddress,
+
+    /// A buffer that the engine writes to does not start and end on a cache
+    /// line boundary, or the buffers differ in length.
+    BadBuffer,
+
+    /// The chain needs a full channel, e.g. for 2D mode.
+    Unsupported,
+
+    /// The transfer failed. Holds the DEBUG register.
+    TransferError(u32),
+}
+
+pub type Result<T> = ::core::result::Result<T, DmaError>;
+
+/// Peripherals that can pace a transfer with their DREQ signal.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum Dreq {
+    PcmTx = 2,
+    PcmRx = 3,
+    Smi = 4,
+    Pwm = 5,
+    SpiTx = 6,
+    SpiRx = 7,
+    BscSpiSlaveTx = 8,
+    BscSpiSlaveRx = 9,
+    Emmc = 11,
+    UartTx = 12,
+    SdHost = 13,
+    UartRx = 14,
+}
+
+/// A DMA control block, as read by the DMA engine.
+#[repr(C, align(32))]
+#[derive(Copy, Clone, Default)]
+pub struct ControlBlock {
+    ti: u32,
+    source_ad: u32,
+    dest_ad: u32,
+    txfr_len: u32,
+    stride: u32,
+    nextconbk: u32,
+    __reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    /// A linear copy of `len` bytes between the bus addresses `src` and
+    /// `dest`.
+    pub fn new(dest: u32, src: u32, len: u32) -> ControlBlock {
+        ControlBlock {
+            ti: (TI::SRC_INC::SET + TI::DEST_INC::SET + TI::WAIT_RESP::SET).value,
+            source_ad: src,
+            dest_ad: dest,
+            txfr_len: len,
+            ..Default::default()
+        }
+    }
+
+    /// Keep reading from the same source address, e.g. a FIFO or a fill
+    /// pattern.
+    pub fn fixed_source(mut self) -> ControlBlock {
+        self.ti &= !TI::SRC_INC::SET.value;
+        self
+    }
+
+    /// Keep writing to the same destination address, e.g. a FIFO.
+    #[allow(dead_code)]
+    pub fn fixed_dest(mut self) -> ControlBlock {
+        self.ti &= !TI::DEST_INC::SET.value;
+        self
+    }
+
+    /// Transfer `rows` rows of `row_len` bytes. After each row, the strides
+    /// are added to the source and destination addresses. Full channels only,
+    /// starting the chain on a lite channel fails.
+    pub fn two_d(
+        mut self,
+        row_len: u16,
+        rows: u16,
+        src_stride: i16,
+        dest_stride: i16,
+    ) -> ControlBlock {
+        // The engine transfers YLENGTH + 1 rows.
+        self.ti |= TI::TDMODE::SET.value;
+        self.txfr_len = u32::from(rows.saturating_sub(1) & 0x3FFF) << 16 | u32::from(row_len);
+        self.stride = u32::from(dest_stride as u16) << 16 | u32::from(src_stride as u16);
+        self
+    }
+
+    /// Only read when the DREQ of `dreq` asks for data.
+    #[allow(dead_code)]
+    pub fn paced_by_source(mut self, dreq: Dreq) -> ControlBlock {
+        self.ti |= (TI::SRC_DREQ::SET + TI::PERMAP.val(dreq as u32)).value;
+        self
+    }
+
+    /// Only write when the DREQ of `dreq` asks for data.
+    #[allow(dead_code)]
+    pub fn paced_by_dest(mut self, dreq: Dreq) -> ControlBlock {
+        self.ti |= (TI::DEST_DREQ::SET + TI::PERMAP.val(dreq as u32)).value;
+        self
+    }
+
+    /// Raise the channel's interrupt when this block is complete.
+    pub fn interrupt(mut self) -> ControlBlock {
+        self.ti |= TI::INTEN::SET.value;
+        self
+    }
+}
+
+// Control blocks are all integers.
+unsafe impl Zeroable for ControlBlock {}
+
+/// A chain of control blocks in the DMA heap, executed back to back.
+pub struct Chain {
+    blocks: DmaSlice<ControlBlock>,
+    len: usize,
+    two_d: bool,
+}
+
+impl Chain {
+    /// Create an empty chain with room for `capacity` control blocks.
+    pub fn new(capacity: usize) -> Result<Chain> {
+        let blocks =
+            DmaSlice::new_zeroed(capacity, CB_ALIGNMENT).map_err(|_| DmaError::OutOfMemory)?;
+
+        Ok(Chain {
+            blocks,
+            len: 0,
+            two_d: false,
+        })
+    }
+
+    /// Append `cb` to the chain.
+    pub fn push(&mut self, mut cb: ControlBlock) -> Result<()> {
+        if self.len == self.blocks.len() {
+            return Err(DmaError::ChainFull);
+        }
+
+        cb.nextconbk = 0;
+        self.blocks[self.len] = cb;
+        self.two_d |= cb.ti & TI::TDMODE::SET.value != 0;
+
+        if self.len > 0 {
+            self.blocks[self.len - 1].nextconbk = self.bus_addr_of(self.len);
+        }
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn bus_addr_of(&self, index: usize) -> u32 {
+        self.blocks.bus_addr() + (index * core::mem::size_of::<ControlBlock>()) as u32
+    }
+}
+
+/// An allocated DMA channel. Returned to the controller on drop.
+pub struct Channel {
+    index: usize,
+    base_addr: usize,
+}
+
+impl ops::Deref for Channel {
+    type Target = ChannelRegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.base_addr as *const _) }
+    }
+}
+
+impl Channel {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Lite channels can only transfer 64 KiB per control block and have no
+    /// 2D mode.
+    pub fn is_lite(&self) -> bool {
+        self.DEBUG.is_set(DEBUG::LITE)
+    }
+
+    /// Maximum length of a single control block on this channel.
+    pub fn max_len(&self) -> usize {
+        if self.is_lite() {
+            MAX_LEN_LITE
+        } else {
+            MAX_LEN_FULL
+        }
+    }
+
+    /// Start executing `chain` and return immediately.
+    ///
+    /// # Safety
+    ///
+    /// - `chain` and all buffers it refers to must stay alive until the
+    ///   transfer is complete.
+    pub unsafe fn start(&mut self, chain: &Chain) -> Result<()> {
+        if chain.two_d && self.is_lite() {
+            return Err(DmaError::Unsupported);
+        }
+
+        self.CS.write(CS::END::SET + CS::INT::SET);
+        self.CONBLK_AD.set(chain.bus_addr_of(0));
+        self.CS.write(
+            CS::ACTIVE::SET
+                + CS::PRIORITY.val(8)
+                + CS::PANIC_PRIORITY.val(15)
+                + CS::WAIT_FOR_OUTSTANDING_WRITES::SET,
+        );
+
+        Ok(())
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.CS.is_set(CS::ACTIVE)
+    }
+
+    /// Wait until the running chain is complete. Sleeps in between checks
+    /// if IRQs are enabled, so the last block should request an interrupt.
+    pub fn wait(&self) -> Result<()> {
+        idle::wait_until(|| !self.is_busy());
+
+        if self.CS.is_set(CS::ERROR) {
+            let debug = self.DEBUG.get();
+
+            // The error bits are write-1-to-clear.
+            self.DEBUG.set(debug & 0b111);
+
+            return Err(DmaError::TransferError(debug));
+        }
+
+        self.CS.write(CS::END::SET);
+
+        Ok(())
+    }
+
+    /// Execute `chain` and wait for it to complete.
+    pub fn run(&mut self, chain: &Chain) -> Result<()> {
+        unsafe { self.start(chain)? };
+        self.wait()
+    }
+
+    /// Stop the running transfer and reset the channel.
+    pub fn abort(&mut self) {
+        if self.is_busy() {
+            self.CS.write(CS::ABORT::SET);
+        }
+
+        self.CS.write(CS::RESET::SET);
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.abort();
+
+        crate::DMA.lock(|d| d.free_channel(self.index));
+    }
+}
+
+/// The GPU interrupt of a channel. Channels 11-14 share one interrupt.
+fn irq_of(channel: usize) -> usize {
+    16 + channel.min(11)
+}
+
+/// Called with the interrupt number as context.
+fn irq_handler(irq: usize) {
+    for channel in 0..NUM_CHANNELS {
+        if irq_of(channel) != irq {
+            continue;
+        }
+
+        let callback = crate::DMA.lock(|d| d.acknowledge(channel));
+        if let Some((func, context)) = callback {
+            func(context);
+        }
+    }
+}
+
+/// Public interface to the DMA controller
+pub struct Dma {
+    base_addr: usize,
+    allocated: u16,
+    callbacks: [Option<(fn(usize), usize)>; NUM_CHANNELS],
+}
+
+impl ops::Deref for Dma {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl Dma {
+    pub const fn new(base_addr: usize) -> Dma {
+        Dma {
+            base_addr,
+            allocated: 0,
+            callbacks: [None; NUM_CHANNELS],
+        }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    /// Install the completion interrupt handlers.
+    pub fn init(&self) -> ::core::result::Result<(), &'static str> {
+        for irq in irq_of(0)..=irq_of(NUM_CHANNELS - 1) {
+            interrupt::register(irq, irq_handler, irq)?;
+        }
+
+        Ok(())
+    }
+
+    /// Allocate a free channel. Full channels are handed out only if `full`
+    /// is requested or no lite channel is left.
+    pub fn alloc_channel(&mut self, full: bool) -> Result<Channel> {
+        let free = USABLE_CHANNELS & !self.allocated;
+
+        let index = (0..NUM_CHANNELS)
+            .filter(|i| free & (1 << i) != 0)
+            .filter(|i| !full || !self.CHANNELS[*i].DEBUG.is_set(DEBUG::LITE))
+            .max()
+            .ok_or(DmaError::NoChannel)?;
+
+        self.allocated |= 1 << index;
+        self.ENABLE.set(self.ENABLE.get() | 1 << index);
+
+        let channel = Channel {
+            index,
+            base_addr: &self.CHANNELS[index] as *const _ as usize,
+        };
+        channel.CS.write(CS::RESET::SET);
+
+        Ok(channel)
+    }
+
+    fn free_channel(&mut self, index: usize) {
+        self.allocated &= !(1 << index);
+        self.callbacks[index] = None;
+    }
+
+    /// Call `func(context)` from the interrupt handler whenever a control
+    /// block of `channel` that requests an interrupt is complete.
+    pub fn set_completion_handler(&mut self, channel: &Channel, func: fn(usize), context: usize) {
+        self.callbacks[channel.index] = Some((func, context));
+    }
+
+    /// Clear a pending interrupt of `channel` and return its callback.
+    fn acknowledge(&mut self, channel: usize) -> Option<(fn(usize), usize)> {
+        let regs = &self.CHANNELS[channel];
+        if !regs.CS.is_set(CS::INT) {
+            return None;
+        }
+
+        regs.CS.write(CS::INT::SET);
+
+        self.callbacks[channel]
+    }
+}
+
+/// The bus address of the kernel buffer at `addr`, if it is contiguous for
+/// `len` bytes.
+fn contiguous_bus_addr(addr: usize, len: usize) -> Result<u32> {
+    let start = bus::to_bus(addr, bus::Alias::Uncached).map_err(|_| DmaError::BadAddress)?;
+    let end =
+        bus::to_bus(addr + len - 1, bus::Alias::Uncached).map_err(|_| DmaError::BadAddress)?;
+
+    if end.wrapping_sub(start) as usize != len - 1 {
+        return Err(DmaError::BadAddress);
+    }
+
+    Ok(start)
+}
+
+/// Run one chain of copies of at most a channel's maximum length from `src`
+/// to `dest`, both bus addresses.
+fn run_split(dest: u32, src: u32, len: usize, fixed_source: bool) -> Result<()> {
+    let mut channel = crate::DMA.lock(|d| d.alloc_channel(false))?;
+    let max_len = channel.max_len();
+
+    let mut chain = Chain::new((len + max_len - 1) / max_len)?;
+    let mut offset = 0;
+    while offset < len {
+        let chunk = (len - offset).min(max_len);
+        let src = if fixed_source {
+            src
+        } else {
+            src + offset as u32
+        };
+
+        let mut cb = ControlBlock::new(dest + offset as u32, src, chunk as u32);
+        if fixed_source {
+            cb = cb.fixed_source();
+        }
+
+        offset += chunk;
+        if offset == len {
+            cb = cb.interrupt();
+        }
+        chain.push(cb)?;
+    }
+
+    channel.run(&chain)
+}
+
+/// Check that the engine can write to `buf` without sharing a cache line with
+/// other data. Invalidating such a line would discard the CPU's writes to the
+/// other data, and cleaning it could overwrite what the engine wrote.
+fn check_dest(buf: &[u8]) -> Result<()> {
+    let mask = cache::dcache_line_size() - 1;
+
+    if buf.as_ptr() as usize & mask != 0 || buf.len() & mask != 0 {
+        return Err(DmaError::BadBuffer);
+    }
+
+    Ok(())
+}
+
+/// Copy `src` to `dest` with the DMA engine.
+///
+/// Both buffers may live in cacheable memory. They are cleaned and
+/// invalidated as needed. `dest` must start and end on a cache line boundary,
+/// and be as long as `src`.
+pub fn dma_memcpy(dest: &mut [u8], src: &[u8]) -> Result<()> {
+    if dest.len() != src.len() {
+        return Err(DmaError::BadBuffer);
+    }
+    check_dest(dest)?;
+
+    let len = dest.len();
+    if len == 0 {
+        return Ok(());
+    }
+
+    let dest_bus = contiguous_bus_addr(dest.as_ptr() as usize, len)?;
+    let src_bus = contiguous_bus_addr(src.as_ptr() as usize, len)?;
+
+    cache::clean_range(src.as_ptr() as usize, len);
+    cache::clean_invalidate_range(dest.as_ptr() as usize, len);
+
+    let ret = run_split(dest_bus, src_bus, len, false);
+
+    // Drop lines that were speculatively fetched during the transfer. `dest`
+    // is borrowed mutably, so the CPU did not write to it.
+    unsafe { cache::invalidate_range(dest.as_ptr() as usize, len) };
+
+    ret
+}
+
+/// Fill `dest` with `value` with the DMA engine. `dest` must start and end on
+/// a cache line boundary.
+pub fn dma_memset(dest: &mut [u8], value: u8) -> Result<()> {
+    check_dest(dest)?;
+
+    let len = dest.len();
+    if len == 0 {
+        return Ok(());
+    }
+
+    let dest_bus = contiguous_bus_addr(dest.as_ptr() as usize, len)?;
+
+    // The engine reads the pattern over and over from the same address.
+    let pattern = DmaBox::new(u32::from(value) * 0x0101_0101).map_err(|_| DmaError::OutOfMemory)?;
+
+    cache::clean_invalidate_range(dest.as_ptr() as usize, len);
+
+    let ret = run_split(dest_bus, pattern.bus_addr(), len, true);
+
+    unsafe { cache::invalidate_range(dest.as_ptr() as usize, len) };
+
+    ret
+}
@@ -0,0 +1,374 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A framebuffer for the HDMI output, allocated by the VideoCore.
+
+use super::videocore_mbox::{tag, PropertyMessage, VideocoreMbox};
+use crate::memory::{
+    self, bus,
+    cache::{self, DmaSync},
+    kernel_mem_range::{AccessPermissions, AttributeFields, MemAttributes},
+};
+use core::ptr;
+
+/// Only 32 bit pixels are supported.
+const DEPTH: u32 = 32;
+const BYTES_PER_PIXEL: usize = 4;
+
+#[derive(Debug)]
+pub enum FramebufferError {
+    MailboxError,
+
+    /// The firmware did not grant the requested mode.
+    Unsupported,
+
+    /// The buffer can not be mapped.
+    MapError(&'static str),
+}
+pub type Result<T> = ::core::result::Result<T, FramebufferError>;
+
+/// The order of the color channels in memory.
+#[derive(Copy, Clone, PartialEq)]
+pub enum PixelOrder {
+    Bgr = 0,
+    Rgb = 1,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xFF, 0xFF, 0xFF);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+}
+
+/// The buffer is not given back to the VideoCore when dropped, use
+/// `release()`.
+pub struct Framebuffer {
+    /// ARM address of the whole buffer, both halves if double buffered.
+    base_addr: usize,
+
+    width: usize,
+    height: usize,
+
+    /// Bytes per line.
+    pitch: usize,
+    pixel_order: PixelOrder,
+
+    double_buffered: bool,
+
+    /// Which half is drawn to, 0 or 1. The other one is shown.
+    back: usize,
+}
+
+impl Framebuffer {
+    /// Let the firmware allocate a `width` x `height` framebuffer.
+    ///
+    /// With `double_buffered`, the virtual height is twice the physical, and
+    /// drawing goes to the half that is not shown until `swap()`. That half is
+    /// mapped cacheable, so drawing is fast. Without, everything drawn shows
+    /// right away, so the buffer is mapped non-cacheable.
+    pub fn new(
+        v_mbox: &mut VideocoreMbox,
+        width: u32,
+        height: u32,
+        double_buffered: bool,
+    ) -> Result<Framebuffer> {
+        let virtual_height = if double_buffered { 2 * height } else { height };
+        let err = |_| FramebufferError::MailboxError;
+
+        // All settings have to go in one message with the allocation.
+        let mut msg = PropertyMessage::new(v_mbox);
+        let physical = msg
+            .push(tag::SetPhysicalSize(tag::Dimensions { width, height }))
+            .map_err(err)?;
+        let virt = msg
+            .push(tag::SetVirtualSize(tag::Dimensions {
+                width,
+                height: virtual_height,
+            }))
+            .map_err(err)?;
+        msg.push(tag::SetVirtualOffset(tag::Dimensions {
+            width: 0,
+            height: 0,
+        }))
+        .map_err(err)?;
+        let depth = msg.push(tag::SetDepth(DEPTH)).map_err(err)?;
+        let order = msg
+            .push(tag::SetPixelOrder(PixelOrder::Rgb as u32))
+            .map_err(err)?;
+        let buffer = msg
+            .push(tag::AllocateBuffer { alignment: 4096 })
+            .map_err(err)?;
+        let pitch = msg.push(tag::GetPitch).map_err(err)?;
+        msg.call().map_err(err)?;
+
+        let physical = msg.get(physical).map_err(err)?;
+        let virt = msg.get(virt).map_err(err)?;
+        let buffer = msg.get(buffer).map_err(err)?;
+        let pitch = msg.get(pitch).map_err(err)? as usize;
+
+        let depth = msg.get(depth).map_err(err)?;
+        let pixel_order = if msg.get(order).map_err(err)? == PixelOrder::Bgr as u32 {
+            PixelOrder::Bgr
+        } else {
+            PixelOrder::Rgb
+        };
+
+        if buffer.base == 0 || buffer.size == 0 {
+            return Err(FramebufferError::Unsupported);
+        }
+
+        let size = buffer.size as usize;
+        let granted = physical.width == width
+            && physical.height == height
+            && virt.width == width
+            && virt.height == virtual_height
+            && depth == DEPTH
+            && pitch >= width as usize * BYTES_PER_PIXEL
+            && size >= pitch * virtual_height as usize;
+
+        let mem_attributes = if double_buffered {
+            MemAttributes::CacheableDRAM
+        } else {
+            MemAttributes::NonCacheableDRAM
+        };
+
+        let ret = if granted {
+            // The firmware hands out a bus address.
+            bus::from_bus(buffer.base)
+                .and_then(|base_addr| {
+                    // The VideoCore reads the buffer behind the caches' back.
+                    memory::remap_kernel_blocks(
+                        base_addr..=base_addr + (size - 1),
+                        AttributeFields {
+                            mem_attributes,
+                            acc_perms: AccessPermissions::ReadWrite,
+                            execute_never: true,
+                        },
+                    )
+                    .map(|()| base_addr)
+                })
+                .map_err(FramebufferError::MapError)
+        } else {
+            Err(FramebufferError::Unsupported)
+        };
+
+        let base_addr = match ret {
+            Ok(base_addr) => base_addr,
+            Err(e) => {
+                let _ = v_mbox.query(tag::ReleaseBuffer);
+                return Err(e);
+            }
+        };
+
+        let mut fb = Framebuffer {
+            base_addr,
+            width: width as usize,
+            height: height as usize,
+            pitch,
+            pixel_order,
+            double_buffered,
+            back: 0,
+        };
+
+        // The firmware does not clear the buffer. Start with the half that is
+        // shown, then draw to the other one.
+        fb.clear(Color::BLACK);
+        fb.sync_for_device();
+        if double_buffered {
+            fb.back = 1;
+            fb.clear(Color::BLACK);
+        }
+
+        Ok(fb)
+    }
+
+    /// Give the buffer back to the VideoCore.
+    pub fn release(self, v_mbox: &mut VideocoreMbox) -> Result<()> {
+        v_mbox
+            .query(tag::ReleaseBuffer)
+            .map(|_| ())
+            .map_err(|_| FramebufferError::MailboxError)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The raw value of `color` in this framebuffer.
+    pub fn pixel_value(&self, color: Color) -> u32 {
+        let (first, third) = match self.pixel_order {
+            PixelOrder::Rgb => (color.r, color.b),
+            PixelOrder::Bgr => (color.b, color.r),
+        };
+
+        u32::from(first) | u32::from(color.g) << 8 | u32::from(third) << 16
+    }
+
+    /// Address of a pixel in the half that is drawn to.
+    fn pixel_ptr(&self, x: usize, y: usize) -> *mut u32 {
+        let line = self.back * self.height + y;
+
+        (self.base_addr + line * self.pitch + x * BYTES_PER_PIXEL) as *mut u32
+    }
+
+    /// Clip a rectangle to the screen. Returns its width and height.
+    fn clip(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        if x >= self.width || y >= self.height {
+            return (0, 0);
+        }
+
+        (width.min(self.width - x), height.min(self.height - y))
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        if x < self.width && y < self.height {
+            unsafe { ptr::write_volatile(self.pixel_ptr(x, y), self.pixel_value(color)) };
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let (width, height) = self.clip(x, y, width, height);
+        let value = self.pixel_value(color);
+
+        for row in y..y + height {
+            let line = self.pixel_ptr(x, row);
+
+            for col in 0..width {
+                unsafe { ptr::write_volatile(line.add(col), value) };
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        let (width, height) = (self.width, self.height);
+
+        self.fill_rect(0, 0, width, height, color);
+    }
+
+    /// Copy `pixels`, which holds raw values in lines of `width` pixels, to
+    /// `x`, `y`.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[u32]) {
+        if width == 0 {
+            return;
+        }
+
+        let (clipped_width, height) = self.clip(x, y, width, pixels.len() / width);
+
+        for row in 0..height {
+            let line = self.pixel_ptr(x, y + row);
+
+            for col in 0..clipped_width {
+                unsafe { ptr::write_volatile(line.add(col), pixels[row * width + col]) };
+            }
+        }
+    }
+
+    /// Invert the colors in a rectangle. Doing it twice restores it, e.g. to
+    /// blink a cursor.
+    pub fn invert_rect(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let (width, height) = self.clip(x, y, width, height);
+        let mask = self.pixel_value(Color::WHITE);
+
+        for row in y..y + height {
+            let line = self.pixel_ptr(x, row);
+
+            for col in 0..width {
+                unsafe {
+                    let pixel = line.add(col);
+                    ptr::write_volatile(pixel, ptr::read_volatile(pixel) ^ mask);
+                }
+            }
+        }
+    }
+
+    /// Move the lines `src_y..src_y + height` to `dest_y`, e.g. to scroll.
+    pub fn copy_lines(&mut self, src_y: usize, dest_y: usize, height: usize) {
+        let height = height
+            .min(self.height.saturating_sub(src_y))
+            .min(self.height.saturating_sub(dest_y));
+        let words = self.width;
+
+        // Copy in the direction that does not overwrite lines before they
+        // are moved.
+        for i in 0..height {
+            let row = if dest_y <= src_y { i } else { height - 1 - i };
+            let src = self.pixel_ptr(0, src_y + row);
+            let dest = self.pixel_ptr(0, dest_y + row);
+
+            for col in 0..words {
+                unsafe { ptr::write_volatile(dest.add(col), ptr::read_volatile(src.add(col))) };
+            }
+        }
+    }
+
+    /// Show what was drawn, and draw to the other half from now on. Does
+    /// nothing if not double buffered.
+    pub fn swap(&mut self, v_mbox: &mut VideocoreMbox) -> Result<()> {
+        if !self.double_buffered {
+            return Ok(());
+        }
+
+        self.sync_for_device();
+        v_mbox
+            .query(tag::SetVirtualOffset(tag::Dimensions {
+                width: 0,
+                height: (self.back * self.height) as u32,
+            }))
+            .map_err(|_| FramebufferError::MailboxError)?;
+
+        self.back ^= 1;
+
+        Ok(())
+    }
+}
+
+/// Covers the half that is drawn to. A no-op if not double buffered, as the
+/// buffer is not cached then.
+impl DmaSync for Framebuffer {
+    fn sync_for_device(&self) {
+        if self.double_buffered {
+            cache::clean_range(self.pixel_ptr(0, 0) as usize, self.height * self.pitch);
+        }
+    }
+
+    unsafe fn sync_for_cpu(&self) {
+        if self.double_buffered {
+            cache::invalidate_range(self.pixel_ptr(0, 0) as usize, self.height * self.pitch);
+        }
+    }
+}
@@ -50,6 +50,23 @@ register_bitfields! {
         ]
     ],
 
+    /// GPIO Function Select 3
+    GPFSEL3 [
+        /// Pin 33
+        FSEL33 OFFSET(9) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            RXD0 = 0b111 // UART0     - Alternate function 3
+        ],
+
+        /// Pin 32
+        FSEL32 OFFSET(6) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            TXD0 = 0b111 // UART0     - Alternate function 3
+        ]
+    ],
+
     /// GPIO Pull-up/down Clock Register 0
     GPPUDCLK0 [
         /// Pin 15
@@ -72,7 +89,7 @@ pub struct RegisterBlock {
     pub GPFSEL0: ReadWrite<u32>,                        // 0x00
     pub GPFSEL1: ReadWrite<u32, GPFSEL1::Register>,     // 0x04
     pub GPFSEL2: ReadWrite<u32>,                        // 0x08
-    pub GPFSEL3: ReadWrite<u32>,                        // 0x0C
+    pub GPFSEL3: ReadWrite<u32, GPFSEL3::Register>,     // 0x0C
     pub GPFSEL4: ReadWrite<u32>,                        // 0x10
     pub GPFSEL5: ReadWrite<u32>,                        // 0x14
     __reserved_0: u32,                                  // 0x18
@@ -95,6 +112,20 @@ pub struct RegisterBlock {
     pub GPPUDCLK1: ReadWrite<u32>,                      // 0x9C
 }
 
+/// Pin functions, with their encoding in the GPFSELn registers.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum Function {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
 /// Public interface to the GPIO MMIO area
 pub struct GPIO {
     base_addr: usize,
@@ -117,4 +148,13 @@ impl GPIO {
     fn ptr(&self) -> *const RegisterBlock {
         self.base_addr as *const _
     }
+
+    /// Select the function of `pin`.
+    pub fn set_function(&self, pin: usize, function: Function) {
+        // Ten pins per GPFSELn register, three bits each.
+        let reg = unsafe { &*((self.base_addr + 4 * (pin / 10)) as *const ReadWrite<u32>) };
+        let shift = 3 * (pin % 10);
+
+        reg.set(reg.get() & !(0b111 << shift) | (function as u32) << shift);
+    }
 }
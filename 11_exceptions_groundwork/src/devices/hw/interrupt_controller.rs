@@ -0,0 +1,104 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use core::ops;
+use register::mmio::*;
+
+// The ARM interrupt controller of the BCM2837. It covers the 64 interrupts
+// of the GPU peripherals, which are routed to core 0 after reset.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    IRQ_BASIC_PENDING: ReadOnly<u32>,   // 0x00
+    IRQ_PENDING_1: ReadOnly<u32>,       // 0x04
+    IRQ_PENDING_2: ReadOnly<u32>,       // 0x08
+    FIQ_CONTROL: ReadWrite<u32>,        // 0x0C
+    ENABLE_IRQS_1: WriteOnly<u32>,      // 0x10
+    ENABLE_IRQS_2: WriteOnly<u32>,      // 0x14
+    ENABLE_BASIC_IRQS: WriteOnly<u32>,  // 0x18
+    DISABLE_IRQS_1: WriteOnly<u32>,     // 0x1C
+    DISABLE_IRQS_2: WriteOnly<u32>,     // 0x20
+    DISABLE_BASIC_IRQS: WriteOnly<u32>, // 0x24
+}
+
+/// Public interface to the interrupt controller MMIO area
+pub struct InterruptController {
+    base_addr: usize,
+}
+
+/// Deref to RegisterBlock
+impl ops::Deref for InterruptController {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl InterruptController {
+    /// Number of GPU peripheral interrupts.
+    pub const NUM_IRQS: usize = 64;
+
+    pub const fn new(base_addr: usize) -> InterruptController {
+        InterruptController { base_addr }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    /// Mask all interrupts.
+    pub fn init(&self) {
+        self.FIQ_CONTROL.set(0);
+        self.DISABLE_IRQS_1.set(!0);
+        self.DISABLE_IRQS_2.set(!0);
+        self.DISABLE_BASIC_IRQS.set(!0);
+    }
+
+    pub fn enable(&self, irq: usize) {
+        if irq < 32 {
+            self.ENABLE_IRQS_1.set(1 << irq);
+        } else {
+            self.ENABLE_IRQS_2.set(1 << (irq - 32));
+        }
+    }
+
+    pub fn disable(&self, irq: usize) {
+        if irq < 32 {
+            self.DISABLE_IRQS_1.set(1 << irq);
+        } else {
+            self.DISABLE_IRQS_2.set(1 << (irq - 32));
+        }
+    }
+
+    /// Bitmask of the pending, enabled interrupts.
+    pub fn pending(&self) -> u64 {
+        u64::from(self.IRQ_PENDING_1.get()) | u64::from(self.IRQ_PENDING_2.get()) << 32
+    }
+}
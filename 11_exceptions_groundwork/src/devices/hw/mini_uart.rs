@@ -22,8 +22,9 @@
  * SOFTWARE.
  */
 
-use super::gpio;
+use super::{gpio, DataBits, FlowControl, Parity, SerialConfig, StopBits};
 use crate::devices::virt::ConsoleOps;
+use crate::{clocks, idle, interrupt, power, ring_buffer::RingBuffer, sync::IrqSafeNullLock};
 use core::ops;
 use cortex_a::asm;
 use register::{mmio::*, register_bitfields};
@@ -45,6 +46,28 @@ register_bitfields! {
         MINI_UART_ENABLE OFFSET(0) NUMBITS(1) []
     ],
 
+    /// Auxiliary Interrupt status
+    AUX_IRQ [
+        /// If set the mini UART has an interrupt pending.
+        MINI_UART_IRQ OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Mini Uart Interrupt Enable
+    ///
+    /// The datasheet has the two enable bits swapped, and leaves out that
+    /// bits 3:2 must be set for any interrupt to be raised.
+    AUX_MU_IER [
+        /// Required to get interrupts at all.
+        LINE_STATUS OFFSET(2) NUMBITS(2) [],
+
+        /// Raise an interrupt while the transmit FIFO is empty.
+        TX_INT OFFSET(1) NUMBITS(1) [],
+
+        /// Raise an interrupt while the receive FIFO holds at least
+        /// 1 symbol.
+        RX_INT OFFSET(0) NUMBITS(1) []
+    ],
+
     /// Mini Uart Interrupt Identify
     AUX_MU_IIR [
         /// Writing with bit 1 set will clear the receive FIFO
@@ -75,6 +98,11 @@ register_bitfields! {
         /// one byte.
         TX_EMPTY   OFFSET(5) NUMBITS(1) [],
 
+        /// This bit is set if there was a receiver overrun, i.e. a
+        /// symbol arrived while the receive FIFO was full. Cleared on
+        /// read.
+        RX_OVERRUN OFFSET(1) NUMBITS(1) [],
+
         /// This bit is set if the receive FIFO holds at least 1
         /// symbol.
         DATA_READY OFFSET(0) NUMBITS(1) []
@@ -82,6 +110,14 @@ register_bitfields! {
 
     /// Mini Uart Extra Control
     AUX_MU_CNTL [
+        /// If this bit is set the mini UART transmitter will stop if the CTS
+        /// line is de-asserted.
+        TX_AUTOFLOW OFFSET(3) NUMBITS(1) [],
+
+        /// If this bit is set the RTS line will de-assert if the receive
+        /// FIFO reaches its 'auto flow' level.
+        RX_AUTOFLOW OFFSET(2) NUMBITS(1) [],
+
         /// If this bit is set the mini UART transmitter is enabled.
         /// If this bit is clear the mini UART transmitter is disabled.
         TX_EN OFFSET(1) NUMBITS(1) [
@@ -107,11 +143,11 @@ register_bitfields! {
 #[allow(non_snake_case)]
 #[repr(C)]
 pub struct RegisterBlock {
-    __reserved_0: u32,                                  // 0x00
+    AUX_IRQ: ReadOnly<u32, AUX_IRQ::Register>,          // 0x00
     AUX_ENABLES: ReadWrite<u32, AUX_ENABLES::Register>, // 0x04
     __reserved_1: [u32; 14],                            // 0x08
     AUX_MU_IO: ReadWrite<u32>,                          // 0x40 - Mini Uart I/O Data
-    AUX_MU_IER: WriteOnly<u32>,                         // 0x44 - Mini Uart Interrupt Enable
+    AUX_MU_IER: ReadWrite<u32, AUX_MU_IER::Register>,   // 0x44
     AUX_MU_IIR: WriteOnly<u32, AUX_MU_IIR::Register>,   // 0x48
     AUX_MU_LCR: WriteOnly<u32, AUX_MU_LCR::Register>,   // 0x4C
     AUX_MU_MCR: WriteOnly<u32>,                         // 0x50
@@ -122,10 +158,131 @@ pub struct RegisterBlock {
     AUX_MU_BAUD: WriteOnly<u32, AUX_MU_BAUD::Register>, // 0x68
 }
 
+/// The GPU interrupt of the auxiliary peripherals, shared with SPI1 and SPI2.
+const IRQ: usize = 29;
+
+/// Receive errors since the driver was initialized.
+#[derive(Copy, Clone)]
+pub struct ErrorCounters {
+    /// The hardware receive FIFO overflowed.
+    pub overrun: u32,
+
+    /// The receive buffer overflowed.
+    pub dropped: u32,
+}
+
+/// Shared between the driver and its interrupt handler.
+struct Buffers {
+    rx: RingBuffer,
+    tx: RingBuffer,
+    errors: ErrorCounters,
+}
+
+static BUFFERS: IrqSafeNullLock<Buffers> = IrqSafeNullLock::new(Buffers {
+    rx: RingBuffer::new(),
+    tx: RingBuffer::new(),
+    errors: ErrorCounters {
+        overrun: 0,
+        dropped: 0,
+    },
+});
+
+/// The baud rate, kept for reprogramming on core clock changes.
+struct Line {
+    baud: u32,
+    notifier: Option<usize>,
+}
+
+static LINE: IrqSafeNullLock<Line> = IrqSafeNullLock::new(Line {
+    baud: 0,
+    notifier: None,
+});
+
+/// Move received characters from the FIFO to the RX buffer.
+fn pump_rx(regs: &RegisterBlock, b: &mut Buffers) {
+    loop {
+        let lsr = regs.AUX_MU_LSR.extract();
+
+        if lsr.is_set(AUX_MU_LSR::RX_OVERRUN) {
+            b.errors.overrun += 1;
+        }
+
+        if !lsr.is_set(AUX_MU_LSR::DATA_READY) {
+            break;
+        }
+
+        if !b.rx.push(regs.AUX_MU_IO.get() as u8) {
+            b.errors.dropped += 1;
+        }
+    }
+}
+
+/// Move queued characters from the TX buffer to the FIFO. The TX interrupt
+/// is only enabled while there is something left to send.
+fn pump_tx(regs: &RegisterBlock, b: &mut Buffers) {
+    while regs.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_EMPTY) {
+        match b.tx.pop() {
+            Some(c) => regs.AUX_MU_IO.set(u32::from(c)),
+            None => break,
+        }
+    }
+
+    if b.tx.is_empty() {
+        regs.AUX_MU_IER.modify(AUX_MU_IER::TX_INT::CLEAR);
+    } else {
+        regs.AUX_MU_IER.modify(AUX_MU_IER::TX_INT::SET);
+    }
+}
+
+/// Called with the base address as context. Both interrupt sources clear
+/// themselves by draining or filling the FIFOs.
+fn irq_handler(base_addr: usize) {
+    let regs = unsafe { &*(base_addr as *const RegisterBlock) };
+
+    // The line is shared with the SPI controllers.
+    if !regs.AUX_IRQ.is_set(AUX_IRQ::MINI_UART_IRQ) {
+        return;
+    }
+
+    BUFFERS.lock(|b| {
+        pump_rx(regs, b);
+        pump_tx(regs, b);
+    });
+}
+
+/// Clock notifier, called with the base address as context.
+fn core_clock_changed(base_addr: usize, core_clk: u32) {
+    let regs = unsafe { &*(base_addr as *const RegisterBlock) };
+
+    // The baud rate was valid at the old clock. If the new clock can't do
+    // it, there is nothing better than to keep the old divisor.
+    if let Ok(reg) = MiniUart::baud_reg(core_clk, LINE.lock(|l| l.baud)) {
+        regs.AUX_MU_BAUD.write(AUX_MU_BAUD::RATE.val(reg));
+    }
+}
+
 pub struct MiniUart {
     base_addr: usize,
 }
 
+#[derive(Debug)]
+pub enum MiniUartError {
+    /// The mini UART only does 7 or 8 data bits, no parity and one stop bit.
+    UnsupportedConfig,
+
+    /// The baud rate can't be derived from the core clock.
+    ClockError,
+
+    /// No free slot to follow core clock changes.
+    NotifierError,
+
+    /// UART1 could not be powered up.
+    PowerError,
+
+    IrqError,
+}
+pub type Result<T> = ::core::result::Result<T, MiniUartError>;
+
 /// Deref to RegisterBlock
 ///
 /// Allows writing
@@ -145,7 +302,11 @@ impl ops::Deref for MiniUart {
 }
 
 impl MiniUart {
-    pub fn new(base_addr: usize) -> MiniUart {
+    /// The core clock the firmware runs at unless told otherwise. The mini
+    /// UART derives its baud rate from it.
+    pub const DEFAULT_CORE_CLOCK: u32 = 250_000_000;
+
+    pub const fn new(base_addr: usize) -> MiniUart {
         MiniUart { base_addr }
     }
 
@@ -154,17 +315,59 @@ impl MiniUart {
         self.base_addr as *const _
     }
 
-    ///Set baud rate and characteristics (115200 8N1) and map to GPIO
-    pub fn init(&self, gpio: &gpio::GPIO) {
+    /// Compute the AUX_MU_BAUD value for `baud` at a core clock of `core_clk`
+    /// Hz, i.e. `core_clk / (8 * (reg + 1)) = baud`, rounded to the nearest.
+    fn baud_reg(core_clk: u32, baud: u32) -> Result<u32> {
+        if baud == 0 {
+            return Err(MiniUartError::ClockError);
+        }
+
+        let div = (u64::from(core_clk) + 4 * u64::from(baud)) / (8 * u64::from(baud));
+        if div == 0 || div > 0x1_0000 {
+            return Err(MiniUartError::ClockError);
+        }
+
+        Ok(div as u32 - 1)
+    }
+
+    /// Set baud rate and characteristics from `config` and map to GPIO.
+    /// `core_clk` is the current core clock in Hz. The baud rate follows
+    /// later changes of the core clock.
+    ///
+    /// Powers up UART1 if the global mailbox is already set up. At boot, it
+    /// is not, and the UART relies on the firmware leaving UART1 powered.
+    pub fn init(&self, gpio: &gpio::GPIO, config: &SerialConfig, core_clk: u32) -> Result<()> {
+        let data_size = match config.data_bits {
+            DataBits::Seven => AUX_MU_LCR::DATA_SIZE::SevenBit,
+            DataBits::Eight => AUX_MU_LCR::DATA_SIZE::EightBit,
+            _ => return Err(MiniUartError::UnsupportedConfig),
+        };
+        if config.parity != Parity::None || config.stop_bits != StopBits::One {
+            return Err(MiniUartError::UnsupportedConfig);
+        }
+        let baud = Self::baud_reg(core_clk, config.baud)?;
+
+        match power::Domain::Uart1.set_shared(true) {
+            Ok(()) | Err(power::PowerError::NoMailbox) => (),
+            Err(_) => return Err(MiniUartError::PowerError),
+        }
+
         // initialize UART
         self.AUX_ENABLES.modify(AUX_ENABLES::MINI_UART_ENABLE::SET);
         self.AUX_MU_IER.set(0);
+
+        // Undo a previous init, which left its notifier and handler behind.
+        if let Some(id) = LINE.lock(|l| l.notifier.take()) {
+            clocks::remove_notifier(id);
+            interrupt::unregister(IRQ);
+        }
+
         self.AUX_MU_CNTL.set(0);
-        self.AUX_MU_LCR.write(AUX_MU_LCR::DATA_SIZE::EightBit);
+        self.AUX_MU_LCR.write(data_size);
         self.AUX_MU_MCR.set(0);
         self.AUX_MU_IER.set(0);
         self.AUX_MU_IIR.write(AUX_MU_IIR::FIFO_CLEAR::All);
-        self.AUX_MU_BAUD.write(AUX_MU_BAUD::RATE.val(270)); // 115200 baud
+        self.AUX_MU_BAUD.write(AUX_MU_BAUD::RATE.val(baud));
 
         // map UART1 to GPIO pins
         gpio.GPFSEL1
@@ -183,79 +386,132 @@ impl MiniUart {
 
         gpio.GPPUDCLK0.set(0);
 
-        self.AUX_MU_CNTL
-            .write(AUX_MU_CNTL::RX_EN::Enabled + AUX_MU_CNTL::TX_EN::Enabled);
+        let mut cntl = AUX_MU_CNTL::RX_EN::Enabled + AUX_MU_CNTL::TX_EN::Enabled;
+        if let FlowControl::RtsCts(pins) = config.flow_control {
+            // CTS1 and RTS1 are ALT5 on both pin pairs
+            let (cts, rts) = pins.pins();
+            gpio.set_function(cts, gpio::Function::Alt5);
+            gpio.set_function(rts, gpio::Function::Alt5);
+
+            cntl = cntl + AUX_MU_CNTL::TX_AUTOFLOW::SET + AUX_MU_CNTL::RX_AUTOFLOW::SET;
+        }
+        self.AUX_MU_CNTL.write(cntl);
 
         // Clear FIFOs before using the device
         self.AUX_MU_IIR.write(AUX_MU_IIR::FIFO_CLEAR::All);
-    }
 
-    pub fn wait_tx_fifo_empty(&self) {
-        loop {
-            if self.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_IDLE) {
-                break;
+        BUFFERS.lock(|b| {
+            b.rx = RingBuffer::new();
+            b.tx = RingBuffer::new();
+            b.errors = ErrorCounters {
+                overrun: 0,
+                dropped: 0,
+            };
+        });
+
+        let notifier = clocks::add_notifier(
+            clocks::Clock::Core,
+            core_clock_changed,
+            self.base_addr,
+            core_clk,
+        )
+        .map_err(|_| MiniUartError::NotifierError)?;
+        LINE.lock(|l| {
+            l.baud = config.baud;
+            l.notifier = Some(notifier);
+        });
+
+        self.AUX_MU_IER
+            .write(AUX_MU_IER::LINE_STATUS::SET + AUX_MU_IER::RX_INT::SET);
+
+        if interrupt::register(IRQ, irq_handler, self.base_addr).is_err() {
+            self.AUX_MU_IER.set(0);
+            if let Some(id) = LINE.lock(|l| l.notifier.take()) {
+                clocks::remove_notifier(id);
             }
 
-            asm::nop();
+            return Err(MiniUartError::IrqError);
         }
+
+        Ok(())
     }
-}
 
-impl Drop for MiniUart {
-    fn drop(&mut self) {
-        self.AUX_ENABLES
-            .modify(AUX_ENABLES::MINI_UART_ENABLE::CLEAR);
+    /// Return a received character, if there is one.
+    pub fn try_read(&self) -> Option<u8> {
+        BUFFERS.lock(|b| {
+            pump_rx(self, b);
+            b.rx.pop()
+        })
     }
-}
 
-impl ConsoleOps for MiniUart {
-    /// Send a character
-    fn putc(&self, c: char) {
-        // wait until we can send
-        loop {
-            if self.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_EMPTY) {
-                break;
-            }
+    /// Queue a character for sending. Returns false if the queue is full.
+    pub fn try_write(&self, c: u8) -> bool {
+        BUFFERS.lock(|b| {
+            let queued = b.tx.push(c);
+            pump_tx(self, b);
 
-            asm::nop();
-        }
+            queued
+        })
+    }
+
+    /// Wait for a character.
+    pub fn read(&self) -> u8 {
+        let mut c = None;
+        idle::wait_until(|| {
+            c = self.try_read();
+            c.is_some()
+        });
 
-        // write the character to the buffer
-        self.AUX_MU_IO.set(c as u32);
+        c.unwrap()
     }
 
-    /// Display a string
-    fn puts(&self, string: &str) {
-        for c in string.chars() {
-            // convert newline to carrige return + newline
-            if c == '\n' {
-                self.putc('\r')
-            }
+    /// Queue a character, waiting for room if necessary.
+    pub fn write(&self, c: u8) {
+        idle::wait_until(|| self.try_write(c));
+    }
 
-            self.putc(c);
-        }
+    /// Receive errors since init.
+    pub fn error_counters(&self) -> ErrorCounters {
+        BUFFERS.lock(|b| b.errors)
     }
 
-    /// Receive a character
-    fn getc(&self) -> char {
-        // wait until something is in the buffer
+    /// Wait until everything queued is on the wire.
+    pub fn wait_tx_fifo_empty(&self) {
+        idle::wait_until(|| {
+            BUFFERS.lock(|b| {
+                pump_tx(self, b);
+                b.tx.is_empty()
+            })
+        });
+
+        // The TX interrupt is off now, so don't sleep.
         loop {
-            if self.AUX_MU_LSR.is_set(AUX_MU_LSR::DATA_READY) {
+            if self.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_IDLE) {
                 break;
             }
 
             asm::nop();
         }
+    }
+}
 
-        // read it and return
-        let mut ret = self.AUX_MU_IO.get() as u8 as char;
+impl ConsoleOps for MiniUart {
+    /// Send a byte
+    fn write_byte(&self, b: u8) {
+        self.write(b);
+    }
 
-        // convert carrige return to newline
-        if ret == '\r' {
-            ret = '\n'
-        }
+    /// Receive a byte
+    fn read_byte(&self) -> u8 {
+        self.read()
+    }
 
-        ret
+    /// Whether a byte was received
+    fn has_input(&self) -> bool {
+        BUFFERS.lock(|b| {
+            pump_rx(self, b);
+            !b.rx.is_empty()
+        })
     }
 
     /// Wait until the TX FIFO is empty, aka all characters have been put on the
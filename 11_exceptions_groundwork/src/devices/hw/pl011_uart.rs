@@ -22,14 +22,13 @@
  * SOFTWARE.
  */
 
-use super::gpio;
 use super::videocore_mbox;
-use crate::delays;
+use super::{gpio, DataBits, FlowControl, Parity, SerialConfig, StopBits};
 use crate::devices::virt::ConsoleOps;
-use core::{
-    ops,
-    sync::atomic::{compiler_fence, Ordering},
+use crate::{
+    clocks, delays, idle, interrupt, power, ring_buffer::RingBuffer, sync::IrqSafeNullLock,
 };
+use core::ops;
 use cortex_a::asm;
 use register::{mmio::*, register_bitfields};
 
@@ -54,7 +53,13 @@ register_bitfields! {
         /// FIFO is disabled, this bit is set when the receive holding
         /// register is empty. If the FIFO is enabled, the RXFE bit is
         /// set when the receive FIFO is empty.
-        RXFE OFFSET(4) NUMBITS(1) []
+        RXFE OFFSET(4) NUMBITS(1) [],
+
+        /// UART busy. If this bit is set to 1, the UART is busy
+        /// transmitting data. This bit remains set until the complete
+        /// byte, including all the stop bits, has been sent from the
+        /// shift register.
+        BUSY OFFSET(3) NUMBITS(1) []
     ],
 
     /// Integer Baud rate divisor
@@ -78,11 +83,42 @@ register_bitfields! {
             SixBit = 0b01,
             SevenBit = 0b10,
             EightBit = 0b11
-        ]
+        ],
+
+        /// Enable FIFOs. If this bit is set to 0, the FIFOs are
+        /// disabled and become 1-byte-deep holding registers.
+        FEN  OFFSET(4) NUMBITS(1) [
+            FifosDisabled = 0,
+            FifosEnabled = 1
+        ],
+
+        /// Two stop bits select. If this bit is set to 1, two stop
+        /// bits are transmitted at the end of the frame.
+        STP2 OFFSET(3) NUMBITS(1) [],
+
+        /// Even parity select. 0 = odd parity, 1 = even parity. Has
+        /// no effect when parity is disabled by PEN.
+        EPS  OFFSET(2) NUMBITS(1) [
+            Odd = 0,
+            Even = 1
+        ],
+
+        /// Parity enable
+        PEN  OFFSET(1) NUMBITS(1) []
     ],
 
     /// Control Register
     CR [
+        /// CTS hardware flow control enable. If this bit is set to 1,
+        /// data is only transmitted when the nUARTCTS signal is
+        /// asserted.
+        CTSEN  OFFSET(15) NUMBITS(1) [],
+
+        /// RTS hardware flow control enable. If this bit is set to 1,
+        /// nUARTRTS is only asserted while there is space in the
+        /// receive FIFO.
+        RTSEN  OFFSET(14) NUMBITS(1) [],
+
         /// Receive enable. If this bit is set to 1, the receive
         /// section of the UART is enabled. Data reception occurs for
         /// UART signals. When the UART is disabled in the middle of
@@ -113,6 +149,45 @@ register_bitfields! {
         ]
     ],
 
+    /// Interrupt FIFO Level Select Register
+    IFLS [
+        /// Receive interrupt FIFO level select. The RX interrupt is
+        /// raised when the receive FIFO becomes at least this full.
+        RXIFLSEL OFFSET(3) NUMBITS(3) [
+            OneEighth = 0b000,
+            OneQuarter = 0b001,
+            OneHalf = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEighths = 0b100
+        ],
+
+        /// Transmit interrupt FIFO level select. The TX interrupt is
+        /// raised when the transmit FIFO becomes at most this full.
+        TXIFLSEL OFFSET(0) NUMBITS(3) [
+            OneEighth = 0b000,
+            OneQuarter = 0b001,
+            OneHalf = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEighths = 0b100
+        ]
+    ],
+
+    /// Interrupt Mask Set/Clear Register
+    IMSC [
+        /// Overrun error interrupt mask
+        OEIM OFFSET(10) NUMBITS(1) [],
+
+        /// Receive timeout interrupt mask. Raised when the receive
+        /// FIFO is not empty, but no more data came in for 32 bits.
+        RTIM OFFSET(6) NUMBITS(1) [],
+
+        /// Transmit interrupt mask
+        TXIM OFFSET(5) NUMBITS(1) [],
+
+        /// Receive interrupt mask
+        RXIM OFFSET(4) NUMBITS(1) []
+    ],
+
     /// Interupt Clear Register
     ICR [
         /// Meta field for all pending interrupts
@@ -131,15 +206,143 @@ pub struct RegisterBlock {
     FBRD: WriteOnly<u32, FBRD::Register>, // 0x28
     LCRH: WriteOnly<u32, LCRH::Register>, // 0x2C
     CR: WriteOnly<u32, CR::Register>,     // 0x30
-    __reserved_2: [u32; 4],               // 0x34
+    IFLS: WriteOnly<u32, IFLS::Register>, // 0x34
+    IMSC: ReadWrite<u32, IMSC::Register>, // 0x38
+    __reserved_2: [u32; 2],               // 0x3C
     ICR: WriteOnly<u32, ICR::Register>,   // 0x44
 }
 
+// Error flags of a received character in DR. A character with a break,
+// parity or framing error is discarded.
+const DR_OE: u32 = 1 << 11;
+const DR_BE: u32 = 1 << 10;
+const DR_PE: u32 = 1 << 9;
+const DR_FE: u32 = 1 << 8;
+
+/// The GPU interrupt of the PL011.
+const IRQ: usize = 57;
+
+/// Receive errors since the driver was initialized.
+#[derive(Copy, Clone)]
+pub struct ErrorCounters {
+    /// The hardware receive FIFO overflowed.
+    pub overrun: u32,
+    pub breaks: u32,
+    pub parity: u32,
+    pub framing: u32,
+
+    /// The receive buffer overflowed.
+    pub dropped: u32,
+}
+
+impl ErrorCounters {
+    const fn new() -> ErrorCounters {
+        ErrorCounters {
+            overrun: 0,
+            breaks: 0,
+            parity: 0,
+            framing: 0,
+            dropped: 0,
+        }
+    }
+}
+
+/// Shared between the driver and its interrupt handler.
+struct Buffers {
+    rx: RingBuffer,
+    tx: RingBuffer,
+    errors: ErrorCounters,
+}
+
+static BUFFERS: IrqSafeNullLock<Buffers> = IrqSafeNullLock::new(Buffers {
+    rx: RingBuffer::new(),
+    tx: RingBuffer::new(),
+    errors: ErrorCounters::new(),
+});
+
+/// Move received characters from the FIFO to the RX buffer.
+fn pump_rx(regs: &RegisterBlock, b: &mut Buffers) {
+    while !regs.FR.is_set(FR::RXFE) {
+        let dr = regs.DR.get();
+
+        if dr & DR_OE != 0 {
+            b.errors.overrun += 1;
+        }
+
+        if dr & DR_BE != 0 {
+            b.errors.breaks += 1;
+        } else if dr & DR_PE != 0 {
+            b.errors.parity += 1;
+        } else if dr & DR_FE != 0 {
+            b.errors.framing += 1;
+        } else if !b.rx.push(dr as u8) {
+            b.errors.dropped += 1;
+        }
+    }
+}
+
+/// Move queued characters from the TX buffer to the FIFO. The TX interrupt
+/// is only enabled while there is something left to send.
+fn pump_tx(regs: &RegisterBlock, b: &mut Buffers) {
+    while !regs.FR.is_set(FR::TXFF) {
+        match b.tx.pop() {
+            Some(c) => regs.DR.set(u32::from(c)),
+            None => break,
+        }
+    }
+
+    if b.tx.is_empty() {
+        regs.IMSC.modify(IMSC::TXIM::CLEAR);
+    } else {
+        regs.IMSC.modify(IMSC::TXIM::SET);
+    }
+}
+
+/// Called with the base address as context.
+fn irq_handler(base_addr: usize) {
+    let regs = unsafe { &*(base_addr as *const RegisterBlock) };
+
+    BUFFERS.lock(|b| {
+        pump_rx(regs, b);
+        pump_tx(regs, b);
+    });
+
+    // RX and TX clear themselves by draining or filling the FIFOs. This is
+    // for the rest.
+    regs.ICR.write(ICR::ALL::SET);
+}
+
 pub enum PL011UartError {
     MailboxError,
+    IrqError,
+
+    /// The UART clock is too slow or too fast for the baud rate, or the baud
+    /// rate is 0.
+    ClockError,
 }
 pub type Result<T> = ::core::result::Result<T, PL011UartError>;
 
+/// The integer and fractional baud rate divisors for `uart_clk`.
+///
+/// The divisor is `uart_clk / (16 * baud)`, with a fractional part of 6 bits.
+fn divisors(uart_clk: u32, baud: u32) -> Result<(u32, u32)> {
+    if baud == 0 {
+        return Err(PL011UartError::ClockError);
+    }
+
+    // Divisor in units of 1/64, rounded to nearest.
+    let div = (u64::from(uart_clk) * 4 + u64::from(baud) / 2) / u64::from(baud);
+
+    let ibrd = (div >> 6) as u32;
+    let fbrd = (div & 0x3F) as u32;
+
+    if ibrd == 0 || ibrd > 0xFFFF {
+        return Err(PL011UartError::ClockError);
+    }
+
+    Ok((ibrd, fbrd))
+}
+
 pub struct PL011Uart {
     base_addr: usize,
 }
@@ -153,7 +356,7 @@ impl ops::Deref for PL011Uart {
 }
 
 impl PL011Uart {
-    pub fn new(base_addr: usize) -> PL011Uart {
+    pub const fn new(base_addr: usize) -> PL011Uart {
         PL011Uart { base_addr }
     }
 
@@ -162,34 +365,37 @@ impl PL011Uart {
         self.base_addr as *const _
     }
 
-    ///Set baud rate and characteristics (115200 8N1) and map to GPIO
+    /// Set baud rate, line format and flow control from `config` and map to
+    /// GPIO. Can be called again to change the settings.
     pub fn init(
         &self,
         v_mbox: &mut videocore_mbox::VideocoreMbox,
         gpio: &gpio::GPIO,
+        config: &SerialConfig,
     ) -> Result<()> {
         // turn off UART0
         self.CR.set(0);
 
-        // set up clock for consistent divisor values
-        v_mbox.buffer[0] = 9 * 4;
-        v_mbox.buffer[1] = videocore_mbox::REQUEST;
-        v_mbox.buffer[2] = videocore_mbox::tag::SETCLKRATE;
-        v_mbox.buffer[3] = 12;
-        v_mbox.buffer[4] = 8;
-        v_mbox.buffer[5] = videocore_mbox::clock::UART; // UART clock
-        v_mbox.buffer[6] = 4_000_000; // 4Mhz
-        v_mbox.buffer[7] = 0; // skip turbo setting
-        v_mbox.buffer[8] = videocore_mbox::tag::LAST;
-
-        // Insert a compiler fence that ensures that all stores to the
-        // mbox buffer are finished before the GPU is signaled (which
-        // is done by a store operation as well).
-        compiler_fence(Ordering::Release);
-
-        if v_mbox.call(videocore_mbox::channel::PROP).is_err() {
-            return Err(PL011UartError::MailboxError); // Abort if UART clocks couldn't be set
-        };
+        // Re-init, e.g. with a new config, installs the handler again.
+        interrupt::unregister(IRQ);
+
+        power::Domain::Uart0
+            .set(v_mbox, true)
+            .map_err(|_| PL011UartError::MailboxError)?;
+
+        // The divisors depend on the UART clock, which is whatever the
+        // firmware chose.
+        let uart_clk =
+            clocks::rate(v_mbox, clocks::Clock::Uart).map_err(|_| PL011UartError::MailboxError)?;
+        let (ibrd, fbrd) = divisors(uart_clk, config.baud)?;
+
+        // On boards with a wireless module, the firmware wires UART0 to the
+        // Bluetooth chip on pins 32 and 33. Disconnect it, so that the chip
+        // does not talk into our RX line.
+        if crate::board::info().map_or(false, |i| i.revision.has_bluetooth()) {
+            gpio.GPFSEL3
+                .modify(gpio::GPFSEL3::FSEL32::Input + gpio::GPFSEL3::FSEL33::Input);
+        }
 
         // map UART0 to GPIO pins
         gpio.GPFSEL1
@@ -205,72 +411,128 @@ impl PL011Uart {
 
         gpio.GPPUDCLK0.set(0);
 
+        let mut cr = CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled;
+        if let FlowControl::RtsCts(pins) = config.flow_control {
+            // CTS0 and RTS0 are ALT3 on both pin pairs
+            let (cts, rts) = pins.pins();
+            gpio.set_function(cts, gpio::Function::Alt3);
+            gpio.set_function(rts, gpio::Function::Alt3);
+
+            cr = cr + CR::CTSEN::SET + CR::RTSEN::SET;
+        }
+
+        let mut lcrh = LCRH::FEN::FifosEnabled
+            + match config.data_bits {
+                DataBits::Five => LCRH::WLEN::FiveBit,
+                DataBits::Six => LCRH::WLEN::SixBit,
+                DataBits::Seven => LCRH::WLEN::SevenBit,
+                DataBits::Eight => LCRH::WLEN::EightBit,
+            };
+        match config.parity {
+            Parity::None => (),
+            Parity::Even => lcrh = lcrh + LCRH::PEN::SET + LCRH::EPS::Even,
+            Parity::Odd => lcrh = lcrh + LCRH::PEN::SET + LCRH::EPS::Odd,
+        }
+        if config.stop_bits == StopBits::Two {
+            lcrh = lcrh + LCRH::STP2::SET;
+        }
+
         self.ICR.write(ICR::ALL::CLEAR);
-        self.IBRD.write(IBRD::IBRD.val(2)); // Results in 115200 baud
-        self.FBRD.write(FBRD::FBRD.val(0xB));
-        self.LCRH.write(LCRH::WLEN::EightBit); // 8N1
+        self.IBRD.write(IBRD::IBRD.val(ibrd));
+        self.FBRD.write(FBRD::FBRD.val(fbrd));
+        self.LCRH.write(lcrh);
 
-        self.CR
-            .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled);
+        BUFFERS.lock(|b| {
+            b.rx = RingBuffer::new();
+            b.tx = RingBuffer::new();
+            b.errors = ErrorCounters::new();
+        });
+        self.IFLS
+            .write(IFLS::RXIFLSEL::OneHalf + IFLS::TXIFLSEL::OneEighth);
+        self.IMSC
+            .write(IMSC::RXIM::SET + IMSC::RTIM::SET + IMSC::OEIM::SET);
+
+        self.CR.write(cr);
+
+        interrupt::register(IRQ, irq_handler, self.base_addr)
+            .map_err(|_| PL011UartError::IrqError)?;
 
         Ok(())
     }
 }
 
-impl Drop for PL011Uart {
-    fn drop(&mut self) {
-        self.CR
-            .write(CR::UARTEN::Disabled + CR::TXE::Disabled + CR::RXE::Disabled);
+impl PL011Uart {
+    /// Return a received character, if there is one.
+    pub fn try_read(&self) -> Option<u8> {
+        BUFFERS.lock(|b| {
+            pump_rx(self, b);
+            b.rx.pop()
+        })
     }
-}
 
-impl ConsoleOps for PL011Uart {
-    /// Send a character
-    fn putc(&self, c: char) {
-        // wait until we can send
-        loop {
-            if !self.FR.is_set(FR::TXFF) {
-                break;
-            }
+    /// Queue a character for sending. Returns false if the queue is full.
+    pub fn try_write(&self, c: u8) -> bool {
+        BUFFERS.lock(|b| {
+            let queued = b.tx.push(c);
+            pump_tx(self, b);
 
-            asm::nop();
-        }
+            queued
+        })
+    }
 
-        // write the character to the buffer
-        self.DR.set(c as u32);
+    /// Wait for a character.
+    pub fn read(&self) -> u8 {
+        let mut c = None;
+        idle::wait_until(|| {
+            c = self.try_read();
+            c.is_some()
+        });
+
+        c.unwrap()
     }
 
-    /// Display a string
-    fn puts(&self, string: &str) {
-        for c in string.chars() {
-            // convert newline to carrige return + newline
-            if c == '\n' {
-                self.putc('\r')
-            }
+    /// Queue a character, waiting for room if necessary.
+    pub fn write(&self, c: u8) {
+        idle::wait_until(|| self.try_write(c));
+    }
 
-            self.putc(c);
-        }
+    /// Receive errors since init.
+    pub fn error_counters(&self) -> ErrorCounters {
+        BUFFERS.lock(|b| b.errors)
     }
+}
 
-    /// Receive a character
-    fn getc(&self) -> char {
-        // wait until something is in the buffer
-        loop {
-            if !self.FR.is_set(FR::RXFE) {
-                break;
-            }
+impl ConsoleOps for PL011Uart {
+    /// Send a byte
+    fn write_byte(&self, b: u8) {
+        self.write(b);
+    }
 
-            asm::nop();
-        }
+    /// Receive a byte
+    fn read_byte(&self) -> u8 {
+        self.read()
+    }
 
-        // read it and return
-        let mut ret = self.DR.get() as u8 as char;
+    /// Whether a byte was received
+    fn has_input(&self) -> bool {
+        BUFFERS.lock(|b| {
+            pump_rx(self, b);
+            !b.rx.is_empty()
+        })
+    }
 
-        // convert carrige return to newline
-        if ret == '\r' {
-            ret = '\n'
+    /// Wait until everything queued is on the wire
+    fn flush(&self) {
+        idle::wait_until(|| {
+            BUFFERS.lock(|b| {
+                pump_tx(self, b);
+                b.tx.is_empty()
+            })
+        });
+
+        // The TX interrupt is off now, so don't sleep.
+        while self.FR.is_set(FR::BUSY) {
+            asm::nop();
         }
-
-        ret
     }
 }
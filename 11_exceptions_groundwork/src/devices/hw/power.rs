@@ -0,0 +1,185 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::gpio;
+use super::videocore_mbox::VideocoreMbox;
+use crate::devices::virt::ConsoleOps;
+use crate::{delays, error, power};
+use core::ops;
+use register::mmio::*;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    pub(super) PM_RSTC: ReadWrite<u32>, // 0x1C
+    pub(super) PM_RSTS: ReadWrite<u32>, // 0x20
+    pub(super) PM_WDOG: ReadWrite<u32>, // 0x24
+}
+
+pub(super) const PM_PASSWORD: u32 = 0x5a_000_000;
+pub(super) const PM_RSTC_WRCFG_CLR: u32 = 0xffff_ffcf;
+pub(super) const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x0000_0020;
+
+// Reset flags in PM_RSTS. The hardware sets HADWRF on a watchdog reset. It
+// would set HADSRF on a software reset, which is not used, so the kernel
+// sets it to tell its own reboots from an expired watchdog.
+pub(super) const PM_RSTS_HADWRF: u32 = 0x0000_0020;
+pub(super) const PM_RSTS_HADSRF: u32 = 0x0000_0200;
+
+// The Raspberry Pi firmware uses the RSTS register to know which
+// partition to boot from. The partition value is spread into bits 0, 2,
+// 4, 6, 8, 10. Partition 63 is a special partition used by the
+// firmware to indicate halt.
+pub(super) const PM_RSTS_RASPBERRYPI_HALT: u32 = 0x555;
+const PM_RSTS_PARTITION_CLR: u32 = 0x555;
+const PARTITION_HALT: u32 = 63;
+
+/// Spread the six bits of `partition` into bits 0, 2, 4, 6, 8, 10.
+fn partition_bits(partition: u32) -> u32 {
+    (0..6).fold(0, |acc, i| acc | ((partition >> i) & 1) << (2 * i))
+}
+
+/// A partition number that the firmware can boot from. Partition 0 is the
+/// default.
+#[derive(Copy, Clone)]
+pub struct Partition(u32);
+
+impl Partition {
+    pub fn new(partition: u32) -> Result<Partition, &'static str> {
+        if partition >= PARTITION_HALT {
+            return Err("Invalid partition number.");
+        }
+
+        Ok(Partition(partition))
+    }
+}
+
+/// Public interface to the Power subsystem
+pub struct Power {
+    base_addr: usize,
+}
+
+impl ops::Deref for Power {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl Power {
+    pub fn new(base_addr: usize) -> Power {
+        Power { base_addr }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    /// Shutdown the board
+    pub fn off(&self, v_mbox: &mut VideocoreMbox, gpio: &gpio::GPIO) -> ! {
+        // The UARTs go last, so that failures can still be reported on
+        // the console.
+        let console = [power::Domain::Uart0, power::Domain::Uart1];
+
+        // power off devices one by one
+        let failed = power::all_off(v_mbox, &console);
+        for (domain, err) in power::Domain::ALL.iter().zip(failed.iter()) {
+            if let Some(e) = err {
+                error!("Could not power off {}: {:?}", domain.name(), e);
+            }
+        }
+
+        crate::CONSOLE.lock(|c| c.flush());
+        for domain in console.iter() {
+            let _ = domain.set(v_mbox, false);
+        }
+
+        // power off gpio pins (but not VCC pins)
+        gpio.GPFSEL0.set(0);
+        gpio.GPFSEL1.set(0);
+        gpio.GPFSEL2.set(0);
+        gpio.GPFSEL3.set(0);
+        gpio.GPFSEL4.set(0);
+        gpio.GPFSEL5.set(0);
+
+        gpio.GPPUD.set(0);
+        delays::wait_cycles(150);
+
+        gpio.GPPUDCLK0.set(0xffff_ffff);
+        gpio.GPPUDCLK1.set(0xffff_ffff);
+        delays::wait_cycles(150);
+
+        // flush GPIO setup
+        gpio.GPPUDCLK0.set(0);
+        gpio.GPPUDCLK1.set(0);
+
+        self.halt();
+    }
+
+    /// Halt the SoC.
+    ///
+    /// Requests the special partition 63 and resets. bootcode.bin then does
+    /// not boot anything, but keeps the SoC in a low power state until the
+    /// power is cycled. Devices are left as they are, see `off()` for a full
+    /// shutdown.
+    pub fn halt(&self) -> ! {
+        // We set the watchdog hard reset bit here to distinguish this
+        // reset from the normal (full) reset. bootcode.bin will not
+        // reboot after a hard reset.
+        let mut val = self.PM_RSTS.get();
+        val |= PM_PASSWORD | PM_RSTS_RASPBERRYPI_HALT;
+        self.PM_RSTS.set(val);
+
+        // Continue with normal reset mechanism
+        self.reset();
+    }
+
+    /// Reboot and let the firmware boot from `partition`, e.g. to switch
+    /// between the A and B images after an update.
+    pub fn reboot_to_partition(&self, partition: Partition) -> ! {
+        let val = self.PM_RSTS.get() & !PM_RSTS_PARTITION_CLR;
+        self.PM_RSTS
+            .set(PM_PASSWORD | val | partition_bits(partition.0));
+
+        self.reset();
+    }
+
+    /// Reboot
+    pub fn reset(&self) -> ! {
+        // Mark the reset as requested, see Watchdog::reset_reason().
+        let val = self.PM_RSTS.get();
+        self.PM_RSTS.set(PM_PASSWORD | val | PM_RSTS_HADSRF);
+
+        // use a timeout of 10 ticks (~150us)
+        self.PM_WDOG.set(PM_PASSWORD | 10);
+        let mut val = self.PM_RSTC.get();
+        val &= PM_RSTC_WRCFG_CLR;
+        val |= PM_PASSWORD | PM_RSTC_WRCFG_FULL_RESET;
+        self.PM_RSTC.set(val);
+
+        loop {}
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+/// Line settings of a UART.
+#[derive(Copy, Clone)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// The GPIO pin pair that carries CTS and RTS.
+#[derive(Copy, Clone, PartialEq)]
+pub enum FlowControlPins {
+    /// CTS on GPIO 16, RTS on GPIO 17.
+    Gpio16And17,
+
+    /// CTS on GPIO 30, RTS on GPIO 31.
+    Gpio30And31,
+}
+
+impl FlowControlPins {
+    /// The (CTS, RTS) pin numbers.
+    pub fn pins(self) -> (usize, usize) {
+        match self {
+            FlowControlPins::Gpio16And17 => (16, 17),
+            FlowControlPins::Gpio30And31 => (30, 31),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum FlowControl {
+    None,
+    RtsCts(FlowControlPins),
+}
+
+/// 115200 baud, 8N1, no flow control.
+impl Default for SerialConfig {
+    fn default() -> SerialConfig {
+        SerialConfig {
+            baud: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use core::ops;
+use register::{mmio::*, register_bitfields};
+
+// The system timer. A free running 64 bit counter at 1 MHz with four compare
+// channels. Channels 0 and 2 are used by the GPU.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+register_bitfields! {
+    u32,
+
+    /// Control/Status
+    CS [
+        /// Set when the counter matched C3. Write 1 to clear.
+        M3 OFFSET(3) NUMBITS(1) [],
+
+        /// Set when the counter matched C1. Write 1 to clear.
+        M1 OFFSET(1) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CS: ReadWrite<u32, CS::Register>, // 0x00
+    CLO: ReadOnly<u32>,               // 0x04
+    CHI: ReadOnly<u32>,               // 0x08
+    C0: ReadWrite<u32>,               // 0x0C
+    C1: ReadWrite<u32>,               // 0x10
+    C2: ReadWrite<u32>,               // 0x14
+    C3: ReadWrite<u32>,               // 0x18
+}
+
+/// Public interface to the system timer MMIO area
+pub struct SystemTimer {
+    base_addr: usize,
+}
+
+/// Deref to RegisterBlock
+impl ops::Deref for SystemTimer {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl SystemTimer {
+    /// The GPU interrupt of compare channel 1.
+    pub const IRQ_C1: usize = 1;
+
+    pub const fn new(base_addr: usize) -> SystemTimer {
+        SystemTimer { base_addr }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    /// Microseconds since reset.
+    pub fn now(&self) -> u64 {
+        // Since it is MMIO, we must emit two separate 32 bit reads, and
+        // repeat if the high word changed in between.
+        loop {
+            let hi = self.CHI.get();
+            let lo = self.CLO.get();
+
+            if hi == self.CHI.get() {
+                return (u64::from(hi) << 32) | u64::from(lo);
+            }
+        }
+    }
+
+    /// Raise IRQ_C1 when the lower 32 bits of the counter reach `value`.
+    pub fn set_compare_c1(&self, value: u32) {
+        self.C1.set(value);
+    }
+
+    /// Clear a match on channel 1.
+    pub fn ack_c1(&self) {
+        self.CS.write(CS::M1::SET);
+    }
+}
@@ -22,7 +22,12 @@
  * SOFTWARE.
  */
 
-use core::ops;
+use crate::memory::dma::DmaSlice;
+use core::{
+    marker::PhantomData,
+    ops,
+    sync::atomic::{compiler_fence, Ordering},
+};
 use cortex_a::asm;
 use register::{
     mmio::{ReadOnly, WriteOnly},
@@ -49,9 +54,17 @@ pub struct RegisterBlock {
 }
 
 // Custom errors
+#[derive(Debug)]
 pub enum VideocoreMboxError {
     ResponseError,
     UnknownError,
+
+    /// The DMA heap has no room for the message.
+    OutOfMemory,
+
+    /// The firmware did not answer a tag, or answered with more data than
+    /// fits its value buffer.
+    TagError,
 }
 pub type Result<T> = ::core::result::Result<T, VideocoreMboxError>;
 
@@ -60,16 +73,12 @@ pub mod channel {
     pub const PROP: u32 = 8;
 }
 
-// Tags
-pub mod tag {
-    pub const SETCLKRATE: u32 = 0x38002;
-    pub const LAST: u32 = 0;
-}
+const REQUEST: u32 = 0;
+const TAG_LAST: u32 = 0;
 
-// Clocks
-pub mod clock {
-    pub const UART: u32 = 0x0_0000_0002;
-}
+/// Set in a tag's request/response code when the firmware answered it. The
+/// lower bits hold the length of the response in bytes.
+const TAG_RESPONSE: u32 = 0x8000_0000;
 
 // Responses
 mod response {
@@ -77,16 +86,14 @@ mod response {
     pub const ERROR: u32 = 0x8000_0001; // error parsing request buffer (partial response)
 }
 
-pub const REQUEST: u32 = 0;
-
 // The address for buffer needs to be 16-byte aligned so that the Videcore can
 // handle it properly.
 const MBOX_ALIGNMENT: usize = 16;
 const MBOX_SIZE: usize = 36;
 
 // Public interface to the mailbox
-pub struct VideocoreMbox<'a> {
-    pub buffer: &'a mut [u32],
+pub struct VideocoreMbox {
+    buffer: DmaSlice<u32>,
     base_addr: usize,
 }
 
@@ -100,7 +107,7 @@ pub struct VideocoreMbox<'a> {
 /// ```
 /// unsafe { (*Mbox::ptr()).STATUS.read() }
 /// ```
-impl<'a> ops::Deref for VideocoreMbox<'a> {
+impl ops::Deref for VideocoreMbox {
     type Target = RegisterBlock;
 
     fn deref(&self) -> &Self::Target {
@@ -108,18 +115,11 @@ impl<'a> ops::Deref for VideocoreMbox<'a> {
     }
 }
 
-impl<'a> VideocoreMbox<'a> {
-    pub fn new(base_addr: usize) -> ::core::result::Result<VideocoreMbox<'a>, ()> {
-        let ret = crate::DMA_ALLOCATOR.lock(|d| d.alloc_slice_zeroed(MBOX_SIZE, MBOX_ALIGNMENT));
+impl VideocoreMbox {
+    pub fn new(base_addr: usize) -> ::core::result::Result<VideocoreMbox, ()> {
+        let buffer = DmaSlice::new_zeroed(MBOX_SIZE, MBOX_ALIGNMENT)?;
 
-        if ret.is_err() {
-            return Err(());
-        }
-
-        Ok(VideocoreMbox {
-            base_addr,
-            buffer: ret.unwrap(),
-        })
+        Ok(VideocoreMbox { base_addr, buffer })
     }
 
     /// Returns a pointer to the register block
@@ -127,6 +127,32 @@ impl<'a> VideocoreMbox<'a> {
         self.base_addr as *const _
     }
 
+    /// Make sure the buffer holds at least `words` words. Existing content
+    /// is kept.
+    fn reserve(&mut self, words: usize) -> Result<()> {
+        if self.buffer.len() >= words {
+            return Ok(());
+        }
+
+        let new_len = words.max(2 * self.buffer.len());
+        let mut buffer = DmaSlice::new_zeroed(new_len, MBOX_ALIGNMENT)
+            .map_err(|_| VideocoreMboxError::OutOfMemory)?;
+        buffer[..self.buffer.len()].copy_from_slice(&self.buffer);
+        self.buffer = buffer;
+
+        Ok(())
+    }
+
+    /// Send a property message with the single tag `t` and return its
+    /// response.
+    pub fn query<T: Tag>(&mut self, t: T) -> Result<T::Response> {
+        let mut msg = PropertyMessage::new(self);
+        let handle = msg.push(t)?;
+        msg.call()?;
+
+        msg.get(handle)
+    }
+
     /// Make a mailbox call. Returns Err(MboxError) on failure, Ok(()) success
     pub fn call(&self, channel: u32) -> Result<()> {
         // wait until we can write to the mailbox
@@ -138,7 +164,12 @@ impl<'a> VideocoreMbox<'a> {
             asm::nop();
         }
 
-        let buf_ptr = self.buffer.as_ptr() as u32;
+        let buf_ptr = self.buffer.bus_addr();
+
+        // Insert a compiler fence that ensures that all stores to the
+        // mbox buffer are finished before the GPU is signaled (which
+        // is done by a store operation as well).
+        compiler_fence(Ordering::Release);
 
         // write the address of our message to the mailbox with channel identifier
         self.WRITE.set((buf_ptr & !0xF) | (channel & 0xF));
@@ -158,6 +189,8 @@ impl<'a> VideocoreMbox<'a> {
 
             // is it a response to our message?
             if ((resp & 0xF) == channel) && ((resp & !0xF) == buf_ptr) {
+                compiler_fence(Ordering::Acquire);
+
                 // is it a valid successful response?
                 return match self.buffer[1] {
                     response::SUCCESS => Ok(()),
@@ -168,3 +201,385 @@ impl<'a> VideocoreMbox<'a> {
         }
     }
 }
+
+/// A property tag of the firmware.
+pub trait Tag {
+    /// The tag identifier.
+    const ID: u32;
+
+    /// Size of the value buffer in words. Must be large enough for both the
+    /// request and the response.
+    const VALUE_WORDS: usize;
+
+    /// The decoded answer of the firmware.
+    type Response;
+
+    /// Write the request values.
+    fn encode(&self, value: &mut [u32]);
+
+    /// Decode the response values.
+    fn decode(value: &[u32]) -> Self::Response;
+}
+
+/// Refers to a tag in a `PropertyMessage` to fetch its response after the
+/// call.
+pub struct Handle<T: Tag> {
+    offset: usize,
+    _marker: PhantomData<T>,
+}
+
+/// A message on the property channel, built from typed tags.
+///
+/// ```ignore
+/// let mut msg = PropertyMessage::new(&mut v_mbox);
+/// let serial = msg.push(tag::GetSerial)?;
+/// msg.call()?;
+/// let serial = msg.get(serial)?;
+/// ```
+pub struct PropertyMessage<'a> {
+    mbox: &'a mut VideocoreMbox,
+
+    /// Words used so far, including the two header words.
+    len: usize,
+}
+
+impl<'a> PropertyMessage<'a> {
+    pub fn new(mbox: &'a mut VideocoreMbox) -> PropertyMessage<'a> {
+        PropertyMessage { mbox, len: 2 }
+    }
+
+    /// Append `tag` to the message.
+    pub fn push<T: Tag>(&mut self, tag: T) -> Result<Handle<T>> {
+        let offset = self.len;
+        let len = offset + 3 + T::VALUE_WORDS;
+
+        // One more word for the end tag.
+        self.mbox.reserve(len + 1)?;
+
+        let buf = &mut self.mbox.buffer;
+        buf[offset] = T::ID;
+        buf[offset + 1] = (T::VALUE_WORDS * 4) as u32;
+        buf[offset + 2] = REQUEST;
+        for word in buf[offset + 3..len].iter_mut() {
+            *word = 0;
+        }
+        tag.encode(&mut buf[offset + 3..len]);
+
+        self.len = len;
+
+        Ok(Handle {
+            offset,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Send the message and wait for the answer.
+    pub fn call(&mut self) -> Result<()> {
+        let buf = &mut self.mbox.buffer;
+        buf[0] = ((self.len + 1) * 4) as u32;
+        buf[1] = REQUEST;
+        buf[self.len] = TAG_LAST;
+
+        self.mbox.call(channel::PROP)
+    }
+
+    /// The response to a tag, after a successful call.
+    pub fn get<T: Tag>(&self, handle: Handle<T>) -> Result<T::Response> {
+        let buf = &self.mbox.buffer;
+        let code = buf[handle.offset + 2];
+
+        if code & TAG_RESPONSE == 0 || (code & !TAG_RESPONSE) as usize > T::VALUE_WORDS * 4 {
+            return Err(VideocoreMboxError::TagError);
+        }
+
+        let value = handle.offset + 3;
+        Ok(T::decode(&buf[value..value + T::VALUE_WORDS]))
+    }
+}
+
+/// The typed property tags.
+pub mod tag {
+    use super::Tag;
+
+    /// Implements Tag for a request without values.
+    macro_rules! get_tag {
+        ($name:ident, $id:expr, $words:expr, $response:ty, |$v:ident| $decode:expr) => {
+            pub struct $name;
+
+            impl Tag for $name {
+                const ID: u32 = $id;
+                const VALUE_WORDS: usize = $words;
+                type Response = $response;
+
+                fn encode(&self, _value: &mut [u32]) {}
+
+                fn decode($v: &[u32]) -> $response {
+                    $decode
+                }
+            }
+        };
+    }
+
+    get_tag!(GetFirmwareRevision, 0x0000_0001, 1, u32, |v| v[0]);
+    get_tag!(GetBoardModel, 0x0001_0001, 1, u32, |v| v[0]);
+    get_tag!(GetBoardRevision, 0x0001_0002, 1, u32, |v| v[0]);
+    get_tag!(GetMacAddress, 0x0001_0003, 2, [u8; 6], |v| {
+        let lo = v[0].to_le_bytes();
+        let hi = v[1].to_le_bytes();
+        [lo[0], lo[1], lo[2], lo[3], hi[0], hi[1]]
+    });
+    get_tag!(GetSerial, 0x0001_0004, 2, u64, |v| u64::from(v[1]) << 32
+        | u64::from(v[0]));
+
+    /// Base address and size of a memory region.
+    #[derive(Copy, Clone)]
+    pub struct MemoryRegion {
+        pub base: u32,
+        pub size: u32,
+    }
+
+    get_tag!(GetArmMemory, 0x0001_0005, 2, MemoryRegion, |v| {
+        MemoryRegion {
+            base: v[0],
+            size: v[1],
+        }
+    });
+    get_tag!(GetVcMemory, 0x0001_0006, 2, MemoryRegion, |v| {
+        MemoryRegion {
+            base: v[0],
+            size: v[1],
+        }
+    });
+
+    /// The state of a power domain.
+    #[derive(Copy, Clone)]
+    pub struct PowerState {
+        pub on: bool,
+        pub exists: bool,
+    }
+
+    impl PowerState {
+        fn decode(state: u32) -> PowerState {
+            PowerState {
+                on: state & 0b01 != 0,
+                exists: state & 0b10 == 0,
+            }
+        }
+    }
+
+    pub struct GetPowerState {
+        pub device: u32,
+    }
+
+    impl Tag for GetPowerState {
+        const ID: u32 = 0x0002_0001;
+        const VALUE_WORDS: usize = 2;
+        type Response = PowerState;
+
+        fn encode(&self, value: &mut [u32]) {
+            value[0] = self.device;
+        }
+
+        fn decode(value: &[u32]) -> PowerState {
+            PowerState::decode(value[1])
+        }
+    }
+
+    pub struct SetPowerState {
+        pub device: u32,
+        pub on: bool,
+
+        /// Let the firmware wait until the power is stable.
+        pub wait: bool,
+    }
+
+    impl Tag for SetPowerState {
+        const ID: u32 = 0x0002_8001;
+        const VALUE_WORDS: usize = 2;
+        type Response = PowerState;
+
+        fn encode(&self, value: &mut [u32]) {
+            value[0] = self.device;
+            value[1] = self.on as u32 | (self.wait as u32) << 1;
+        }
+
+        fn decode(value: &[u32]) -> PowerState {
+            PowerState::decode(value[1])
+        }
+    }
+
+    /// Implements Tag for a request that names a clock and gets back one
+    /// value for it.
+    macro_rules! clock_tag {
+        ($name:ident, $id:expr) => {
+            pub struct $name {
+                pub clock: u32,
+            }
+
+            impl Tag for $name {
+                const ID: u32 = $id;
+                const VALUE_WORDS: usize = 2;
+                type Response = u32;
+
+                fn encode(&self, value: &mut [u32]) {
+                    value[0] = self.clock;
+                }
+
+                fn decode(value: &[u32]) -> u32 {
+                    value[1]
+                }
+            }
+        };
+    }
+
+    // Bit 0 of the state: on. Bit 1: the clock does not exist.
+    clock_tag!(GetClockState, 0x0003_0001);
+    clock_tag!(GetClockRate, 0x0003_0002);
+    clock_tag!(GetMaxClockRate, 0x0003_0004);
+    clock_tag!(GetMinClockRate, 0x0003_0007);
+
+    // The temperature tags take a sensor ID, which is always 0. The
+    // temperatures are in thousandths of a degree Celsius.
+    get_tag!(GetTemperature, 0x0003_0006, 2, u32, |v| v[1]);
+    get_tag!(GetMaxTemperature, 0x0003_000A, 2, u32, |v| v[1]);
+
+    // The throttling flags, see `thermal::Throttled`.
+    get_tag!(GetThrottled, 0x0003_0046, 1, u32, |v| v[0]);
+
+    // The turbo tags take an ID, which is always 0.
+    get_tag!(GetTurbo, 0x0003_0009, 2, bool, |v| v[1] != 0);
+
+    pub struct SetTurbo {
+        pub on: bool,
+    }
+
+    impl Tag for SetTurbo {
+        const ID: u32 = 0x0003_8009;
+        const VALUE_WORDS: usize = 2;
+        type Response = u32;
+
+        fn encode(&self, value: &mut [u32]) {
+            value[0] = 0;
+            value[1] = self.on as u32;
+        }
+
+        fn decode(value: &[u32]) -> u32 {
+            value[1]
+        }
+    }
+
+    pub struct SetClockRate {
+        pub clock: u32,
+        pub hz: u32,
+        pub skip_turbo: bool,
+    }
+
+    impl Tag for SetClockRate {
+        const ID: u32 = 0x0003_8002;
+        const VALUE_WORDS: usize = 3;
+        type Response = u32;
+
+        fn encode(&self, value: &mut [u32]) {
+            value[0] = self.clock;
+            value[1] = self.hz;
+            value[2] = self.skip_turbo as u32;
+        }
+
+        fn decode(value: &[u32]) -> u32 {
+            value[1]
+        }
+    }
+
+    /// Width and height, or an x and y offset, in pixels.
+    #[derive(Copy, Clone)]
+    pub struct Dimensions {
+        pub width: u32,
+        pub height: u32,
+    }
+
+    /// Implements Tag for a request that sets a pair of values and gets back
+    /// what the firmware chose.
+    macro_rules! dimensions_tag {
+        ($name:ident, $id:expr) => {
+            pub struct $name(pub Dimensions);
+
+            impl Tag for $name {
+                const ID: u32 = $id;
+                const VALUE_WORDS: usize = 2;
+                type Response = Dimensions;
+
+                fn encode(&self, value: &mut [u32]) {
+                    value[0] = self.0.width;
+                    value[1] = self.0.height;
+                }
+
+                fn decode(value: &[u32]) -> Dimensions {
+                    Dimensions {
+                        width: value[0],
+                        height: value[1],
+                    }
+                }
+            }
+        };
+    }
+
+    dimensions_tag!(SetPhysicalSize, 0x0004_8003);
+    dimensions_tag!(SetVirtualSize, 0x0004_8004);
+    dimensions_tag!(SetVirtualOffset, 0x0004_8009);
+
+    /// Implements Tag for a request that sets a single value and gets back
+    /// what the firmware chose.
+    macro_rules! set_tag {
+        ($name:ident, $id:expr) => {
+            pub struct $name(pub u32);
+
+            impl Tag for $name {
+                const ID: u32 = $id;
+                const VALUE_WORDS: usize = 1;
+                type Response = u32;
+
+                fn encode(&self, value: &mut [u32]) {
+                    value[0] = self.0;
+                }
+
+                fn decode(value: &[u32]) -> u32 {
+                    value[0]
+                }
+            }
+        };
+    }
+
+    // Bits per pixel.
+    set_tag!(SetDepth, 0x0004_8005);
+
+    // 0: BGR, 1: RGB.
+    set_tag!(SetPixelOrder, 0x0004_8006);
+
+    // Bytes per line.
+    get_tag!(GetPitch, 0x0004_0008, 1, u32, |v| v[0]);
+
+    /// Allocate the framebuffer with the given alignment. Returns its bus
+    /// address and size.
+    pub struct AllocateBuffer {
+        pub alignment: u32,
+    }
+
+    impl Tag for AllocateBuffer {
+        const ID: u32 = 0x0004_0001;
+        const VALUE_WORDS: usize = 2;
+        type Response = MemoryRegion;
+
+        fn encode(&self, value: &mut [u32]) {
+            value[0] = self.alignment;
+        }
+
+        fn decode(value: &[u32]) -> MemoryRegion {
+            MemoryRegion {
+                base: value[0],
+                size: value[1],
+            }
+        }
+    }
+
+    get_tag!(ReleaseBuffer, 0x0004_8001, 0, (), |_v| ());
+}
@@ -0,0 +1,185 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::power::{
+    RegisterBlock, PM_PASSWORD, PM_RSTC_WRCFG_CLR, PM_RSTC_WRCFG_FULL_RESET, PM_RSTS_HADSRF,
+    PM_RSTS_HADWRF, PM_RSTS_RASPBERRYPI_HALT,
+};
+use crate::{sync::IrqSafeNullLock, timer};
+use core::ops;
+
+/// Stops the watchdog.
+const PM_RSTC_RESET: u32 = 0x0000_0102;
+
+/// The watchdog counts down at 65536 Hz, in a 20 bit register.
+const PM_WDOG_TIME_MASK: u32 = 0x000F_FFFF;
+const TICKS_PER_SEC: u64 = 65536;
+
+/// Set by the hardware on a power-on reset.
+const PM_RSTS_HADPOR: u32 = 0x0000_1000;
+
+/// The configured timeout in ticks, and the ID of the timer callback that
+/// feeds the watchdog.
+struct State {
+    timeout_ticks: u32,
+    keep_alive: Option<usize>,
+}
+
+static STATE: IrqSafeNullLock<State> = IrqSafeNullLock::new(State {
+    timeout_ticks: 0,
+    keep_alive: None,
+});
+
+/// Why the board was last reset.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ResetReason {
+    PowerOn,
+
+    /// The watchdog expired.
+    Watchdog,
+
+    /// A reboot was requested, e.g. from the shell.
+    Reboot,
+
+    /// A halt was requested, and the power was cycled afterwards.
+    Halt,
+    Unknown,
+}
+
+impl ResetReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power-on",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Reboot => "requested reboot",
+            ResetReason::Halt => "requested halt",
+            ResetReason::Unknown => "unknown",
+        }
+    }
+}
+
+/// Public interface to the watchdog, which lives in the power management
+/// block.
+pub struct Watchdog {
+    base_addr: usize,
+}
+
+impl ops::Deref for Watchdog {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+/// Timer callback. The context is the base address.
+fn feed(base_addr: usize) {
+    Watchdog::new(base_addr).pet();
+}
+
+impl Watchdog {
+    /// Longest timeout the hardware supports, about 16 seconds.
+    pub const MAX_TIMEOUT_MS: u64 = PM_WDOG_TIME_MASK as u64 * 1000 / TICKS_PER_SEC;
+
+    pub const fn new(base_addr: usize) -> Watchdog {
+        Watchdog { base_addr }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    /// Reset the board unless `pet()` is called at least every
+    /// `timeout_ms`.
+    pub fn start(&self, timeout_ms: u64) {
+        let ticks = (timeout_ms.min(Self::MAX_TIMEOUT_MS) * TICKS_PER_SEC / 1000) as u32;
+        STATE.lock(|s| s.timeout_ticks = ticks);
+
+        self.PM_WDOG.set(PM_PASSWORD | ticks);
+
+        let val = self.PM_RSTC.get() & PM_RSTC_WRCFG_CLR;
+        self.PM_RSTC
+            .set(PM_PASSWORD | val | PM_RSTC_WRCFG_FULL_RESET);
+    }
+
+    /// Restart the countdown.
+    pub fn pet(&self) {
+        // PM_WDOG reads back the remaining time, so use the stored timeout.
+        let ticks = STATE.lock(|s| s.timeout_ticks);
+
+        self.PM_WDOG.set(PM_PASSWORD | ticks);
+    }
+
+    /// Disable the watchdog and its keep-alive callback.
+    pub fn stop(&self) {
+        if let Some(id) = STATE.lock(|s| s.keep_alive.take()) {
+            timer::remove_periodic(id);
+        }
+
+        self.PM_RSTC.set(PM_PASSWORD | PM_RSTC_RESET);
+    }
+
+    /// Start the watchdog and pet it from a timer callback four times per
+    /// timeout. The board is reset if the timer interrupt stops, e.g. when
+    /// the kernel hangs with interrupts masked.
+    pub fn start_with_keep_alive(&self, timeout_ms: u64) -> Result<(), &'static str> {
+        self.start(timeout_ms);
+
+        let period_us = timeout_ms.min(Self::MAX_TIMEOUT_MS) * 1000 / 4;
+        let id = timer::add_periodic(period_us, feed, self.base_addr)?;
+
+        if let Some(old) = STATE.lock(|s| s.keep_alive.replace(id)) {
+            timer::remove_periodic(old);
+        }
+
+        Ok(())
+    }
+
+    /// Why the board was last reset, according to PM_RSTS.
+    ///
+    /// The flags stay set across resets, so they are cleared here. Call this
+    /// once during boot.
+    pub fn reset_reason(&self) -> ResetReason {
+        let rsts = self.PM_RSTS.get();
+
+        let reason = if rsts & PM_RSTS_RASPBERRYPI_HALT == PM_RSTS_RASPBERRYPI_HALT {
+            ResetReason::Halt
+        } else if rsts & PM_RSTS_HADSRF != 0 {
+            ResetReason::Reboot
+        } else if rsts & PM_RSTS_HADWRF != 0 {
+            ResetReason::Watchdog
+        } else if rsts & PM_RSTS_HADPOR != 0 {
+            ResetReason::PowerOn
+        } else {
+            ResetReason::Unknown
+        };
+
+        // The partition bits are left to the firmware.
+        let flags = PM_RSTS_HADPOR | PM_RSTS_HADWRF | PM_RSTS_HADSRF;
+        self.PM_RSTS.set(PM_PASSWORD | (rsts & !flags));
+
+        reason
+    }
+}
@@ -23,5 +23,7 @@
  */
 
 mod console;
+mod fb_console;
 
-pub use console::{Console, ConsoleOps};
+pub use console::{command_prompt, Console, ConsoleOps};
+pub use fb_console::FbConsole;
@@ -22,112 +22,228 @@
  * SOFTWARE.
  */
 
-use crate::devices::hw;
+use crate::{idle, sync::NullLock};
 use core::fmt;
+use line_editor::LineEditor;
+
+mod line_editor;
+
+/// Replaces input that is not valid UTF-8.
+const REPLACEMENT_CHAR: char = '\u{FFFD}';
 
 /// A trait that must be implemented by devices that are candidates for the
 /// global console.
+///
+/// Devices move bytes. Characters are UTF-8 encoded and decoded on top of
+/// that by the provided methods.
 #[allow(unused_variables)]
-pub trait ConsoleOps: Drop {
-    fn putc(&self, c: char) {}
-    fn puts(&self, string: &str) {}
-    fn getc(&self) -> char {
-        ' '
+pub trait ConsoleOps {
+    /// Send a byte
+    fn write_byte(&self, b: u8) {}
+
+    /// Receive a byte
+    fn read_byte(&self) -> u8 {
+        b' '
+    }
+
+    /// Whether `read_byte()` returns without waiting
+    fn has_input(&self) -> bool {
+        false
     }
+
     fn flush(&self) {}
-}
 
-/// A dummy console that just ignores its inputs.
-pub struct NullConsole;
-impl Drop for NullConsole {
-    fn drop(&mut self) {}
-}
-impl ConsoleOps for NullConsole {}
+    /// Send a character
+    fn putc(&self, c: char) {
+        let mut buf = [0; 4];
 
-/// Possible outputs which the console can store.
-pub enum Output {
-    None(NullConsole),
-    MiniUart(hw::MiniUart),
-    PL011Uart(hw::PL011Uart),
-}
+        for b in c.encode_utf8(&mut buf).bytes() {
+            self.write_byte(b);
+        }
+    }
 
-impl From<hw::MiniUart> for Output {
-    fn from(instance: hw::MiniUart) -> Self {
-        Output::MiniUart(instance)
+    /// Display a string
+    fn puts(&self, string: &str) {
+        for b in string.bytes() {
+            // convert newline to carrige return + newline
+            if b == b'\n' {
+                self.write_byte(b'\r');
+            }
+
+            self.write_byte(b);
+        }
     }
-}
 
-impl From<hw::PL011Uart> for Output {
-    fn from(instance: hw::PL011Uart) -> Self {
-        Output::PL011Uart(instance)
+    /// Receive a character, assembling multi-byte UTF-8 sequences.
+    ///
+    /// Malformed input gives U+FFFD. A byte that breaks off a sequence is
+    /// swallowed with it.
+    fn read_char(&self) -> char {
+        let first = self.read_byte();
+
+        let (len, bits) = match first {
+            0x00..=0x7F => return first as char,
+            0xC2..=0xDF => (2, first & 0x1F),
+            0xE0..=0xEF => (3, first & 0x0F),
+            0xF0..=0xF4 => (4, first & 0x07),
+            _ => return REPLACEMENT_CHAR,
+        };
+
+        let mut code = u32::from(bits);
+        for _ in 1..len {
+            let b = self.read_byte();
+            if b & 0xC0 != 0x80 {
+                return REPLACEMENT_CHAR;
+            }
+
+            code = code << 6 | u32::from(b & 0x3F);
+        }
+
+        // Overlong encodings
+        if (len == 3 && code < 0x800) || (len == 4 && code < 0x1_0000) {
+            return REPLACEMENT_CHAR;
+        }
+
+        // Also rejects surrogates and anything above U+10FFFF.
+        core::char::from_u32(code).unwrap_or(REPLACEMENT_CHAR)
+    }
+
+    /// Receive a character
+    fn getc(&self) -> char {
+        match self.read_char() {
+            // convert carrige return to newline
+            '\r' => '\n',
+            c => c,
+        }
     }
 }
 
+/// How many devices the console can mirror its output to.
+const MAX_OUTPUTS: usize = 4;
+
+/// The kernel console. Output goes to every registered device, input comes
+/// from one selected device.
+///
+/// Devices are registered by reference, so they must live in statics. They
+/// stay initialized when they are removed from the console.
 pub struct Console {
-    output: Output,
+    outputs: [Option<&'static dyn ConsoleOps>; MAX_OUTPUTS],
+    input: Option<&'static dyn ConsoleOps>,
 }
 
 impl Console {
     pub const fn new() -> Console {
         Console {
-            output: Output::None(NullConsole {}),
+            outputs: [None; MAX_OUTPUTS],
+            input: None,
         }
     }
 
-    #[inline(always)]
-    fn current_ptr(&self) -> &dyn ConsoleOps {
-        match &self.output {
-            Output::None(i) => i,
-            Output::MiniUart(i) => i,
-            Output::PL011Uart(i) => i,
-        }
-    }
+    /// Mirror the output to `dev`. Returns an ID for `remove_output()`.
+    pub fn add_output(&mut self, dev: &'static dyn ConsoleOps) -> Result<usize, &'static str> {
+        let id = self
+            .outputs
+            .iter()
+            .position(|o| o.is_none())
+            .ok_or("No free console output slot.")?;
 
-    /// Overwrite the current output. The old output will go out of scope and
-    /// it's Drop function will be called.
-    pub fn replace_with(&mut self, x: Output) {
-        self.current_ptr().flush();
+        self.outputs[id] = Some(dev);
 
-        self.output = x;
+        Ok(id)
     }
 
-    /// A command prompt. Currently does nothing.
-    pub fn command_prompt(&self) -> ! {
-        self.puts("\n$> ");
+    /// Stop sending output to a device, after flushing it.
+    pub fn remove_output(&mut self, id: usize) {
+        if let Some(dev) = self.outputs.get_mut(id).and_then(|o| o.take()) {
+            dev.flush();
+        }
+    }
 
-        let mut input;
-        loop {
-            input = self.getc();
+    /// Whether anything is listening.
+    pub fn has_outputs(&self) -> bool {
+        self.outputs.iter().any(|o| o.is_some())
+    }
 
-            if input == '\n' {
-                self.puts("\n$> ")
-            } else {
-                self.putc(input);
-            }
+    /// Read input from `dev`, or from nowhere.
+    pub fn set_input(&mut self, dev: Option<&'static dyn ConsoleOps>) {
+        self.input = dev;
+    }
+
+    fn for_each_output<F>(&self, f: F)
+    where
+        F: Fn(&dyn ConsoleOps),
+    {
+        for dev in self.outputs.iter().filter_map(|o| *o) {
+            f(dev);
         }
     }
 }
 
-impl Drop for Console {
-    fn drop(&mut self) {}
+/// A command prompt. Reads lines with a `LineEditor` and hands them to
+/// `dispatch`.
+///
+/// The console is only locked while reading and echoing, so that `dispatch`
+/// may print. Deferred work runs while waiting for input.
+pub fn command_prompt(console: &NullLock<Console>, dispatch: fn(&str)) -> ! {
+    let mut editor = LineEditor::new("$> ");
+
+    console.lock(|c| {
+        c.puts("\n");
+        editor.prompt(c);
+    });
+
+    loop {
+        idle::wait_until(|| idle::has_deferred() || console.lock(|c| c.has_input()));
+        idle::run_deferred();
+
+        if !console.lock(|c| c.has_input()) {
+            continue;
+        }
+
+        let input = console.lock(|c| c.getc());
+
+        if console.lock(|c| editor.feed(c, input)) {
+            dispatch(editor.line());
+            console.lock(|c| editor.prompt(c));
+        }
+    }
 }
 
-/// Dispatch the respective function to the currently stored output device.
+/// Fan out to all outputs, and read from the input.
 impl ConsoleOps for Console {
-    fn putc(&self, c: char) {
-        self.current_ptr().putc(c);
+    fn write_byte(&self, b: u8) {
+        self.for_each_output(|dev| dev.write_byte(b));
     }
 
-    fn puts(&self, string: &str) {
-        self.current_ptr().puts(string);
+    /// Without an input, behaves like a device that only ever receives
+    /// spaces.
+    fn read_byte(&self) -> u8 {
+        match self.input {
+            Some(dev) => dev.read_byte(),
+            None => b' ',
+        }
     }
 
-    fn getc(&self) -> char {
-        self.current_ptr().getc()
+    /// Without an input, there never is any, so that the prompt idles.
+    fn has_input(&self) -> bool {
+        match self.input {
+            Some(dev) => dev.has_input(),
+            None => false,
+        }
+    }
+
+    // Whole characters and strings go to the devices in one piece, in case
+    // they do more with them than pushing out bytes.
+    fn putc(&self, c: char) {
+        self.for_each_output(|dev| dev.putc(c));
+    }
+
+    fn puts(&self, string: &str) {
+        self.for_each_output(|dev| dev.puts(string));
     }
 
     fn flush(&self) {
-        self.current_ptr().flush()
+        self.for_each_output(|dev| dev.flush());
     }
 }
 
@@ -137,7 +253,7 @@ impl ConsoleOps for Console {
 /// See src/macros.rs.
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.current_ptr().puts(s);
+        self.puts(s);
 
         Ok(())
     }
@@ -0,0 +1,376 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Line editing for the serial console.
+//!
+//! Understands the keys of a VT100-style terminal: printable characters,
+//! including non-ASCII ones, backspace, delete, the arrow keys, home and end,
+//! as well as Ctrl-A/E (home/end), Ctrl-C (drop the line), Ctrl-U (erase up
+//! to the cursor) and Ctrl-L (clear the screen). Up and down walk through a
+//! history of the last entered lines.
+
+use super::ConsoleOps;
+
+/// The longest line that can be entered, in bytes of UTF-8.
+pub const MAX_LINE: usize = 128;
+
+/// How many lines the history remembers.
+const HISTORY_LEN: usize = 8;
+
+#[derive(Copy, Clone)]
+struct Line {
+    buf: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl Line {
+    const fn new() -> Line {
+        Line {
+            buf: [0; MAX_LINE],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Progress through an escape sequence.
+#[derive(Copy, Clone)]
+enum Escape {
+    None,
+
+    /// Got ESC.
+    Start,
+
+    /// Got ESC [ and the numeric parameter so far.
+    Csi(u32),
+
+    /// Got ESC O.
+    Ss3,
+}
+
+pub struct LineEditor {
+    prompt: &'static str,
+    line: Line,
+
+    /// Byte offset into the line, always on a character boundary. The
+    /// terminal cursor is assumed to advance by one column per character.
+    cursor: usize,
+    escape: Escape,
+
+    /// Ring of entered lines. `history_next` is the slot that is written
+    /// next.
+    history: [Line; HISTORY_LEN],
+    history_next: usize,
+    history_count: usize,
+
+    /// How far back in the history the shown line is, 0 being the line that
+    /// is being edited. That one is kept in `stash` while browsing.
+    browsing: usize,
+    stash: Line,
+}
+
+impl LineEditor {
+    pub fn new(prompt: &'static str) -> LineEditor {
+        LineEditor {
+            prompt,
+            line: Line::new(),
+            cursor: 0,
+            escape: Escape::None,
+            history: [Line::new(); HISTORY_LEN],
+            history_next: 0,
+            history_count: 0,
+            browsing: 0,
+            stash: Line::new(),
+        }
+    }
+
+    /// Start a new, empty line.
+    pub fn prompt(&mut self, out: &dyn ConsoleOps) {
+        self.line.len = 0;
+        self.cursor = 0;
+        self.escape = Escape::None;
+        self.browsing = 0;
+
+        out.puts(self.prompt);
+    }
+
+    /// The line that was completed by the last `feed()`.
+    pub fn line(&self) -> &str {
+        // Only whole characters get in.
+        core::str::from_utf8(self.line.as_bytes()).unwrap_or("")
+    }
+
+    /// Process one received character, echoing to `out`. Returns true when
+    /// the line was completed. Call `prompt()` before feeding the next one.
+    pub fn feed(&mut self, out: &dyn ConsoleOps, c: char) -> bool {
+        match self.escape {
+            Escape::None => (),
+            Escape::Start => {
+                self.escape = match c {
+                    '[' => Escape::Csi(0),
+                    'O' => Escape::Ss3,
+                    _ => Escape::None,
+                };
+                return false;
+            }
+            Escape::Csi(param) => {
+                match c {
+                    '0'..='9' => {
+                        let digit = c as u32 - '0' as u32;
+                        self.escape = Escape::Csi(param.saturating_mul(10).saturating_add(digit));
+                    }
+
+                    // Intermediate and further parameter bytes; nothing
+                    // understood here needs them.
+                    ' '..='?' => (),
+
+                    _ => {
+                        self.escape = Escape::None;
+                        self.csi(out, param, c);
+                    }
+                }
+                return false;
+            }
+            Escape::Ss3 => {
+                self.escape = Escape::None;
+                self.csi(out, 0, c);
+                return false;
+            }
+        }
+
+        match c {
+            '\n' => {
+                out.puts("\n");
+                self.remember();
+
+                return true;
+            }
+            '\x7f' | '\x08' => self.backspace(out),
+            '\x1b' => self.escape = Escape::Start,
+            '\x01' => self.home(out),
+            '\x05' => self.end(out),
+            '\x03' => {
+                out.puts("^C\n");
+                self.prompt(out);
+            }
+            '\x15' => self.erase_to_start(out),
+            '\x0c' => self.redraw(out),
+            c if !c.is_control() => self.insert(out, c),
+            _ => (),
+        }
+
+        false
+    }
+
+    /// Final byte of a CSI or SS3 sequence.
+    fn csi(&mut self, out: &dyn ConsoleOps, param: u32, c: char) {
+        match (c, param) {
+            ('A', _) => self.history_up(out),
+            ('B', _) => self.history_down(out),
+            ('C', _) => self.right(out),
+            ('D', _) => self.left(out),
+            ('H', _) | ('~', 1) | ('~', 7) => self.home(out),
+            ('F', _) | ('~', 4) | ('~', 8) => self.end(out),
+            ('~', 3) => self.delete(out),
+            _ => (),
+        }
+    }
+
+    /// Move the terminal cursor back over the characters in `from..to`.
+    fn back(&self, out: &dyn ConsoleOps, from: usize, to: usize) {
+        for _ in self.text(from, to).chars() {
+            out.putc('\x08');
+        }
+    }
+
+    /// Length of the character that starts at byte `at`.
+    fn char_len(&self, at: usize) -> usize {
+        self.text(at, self.line.len)
+            .chars()
+            .next()
+            .map_or(0, |c| c.len_utf8())
+    }
+
+    fn text(&self, from: usize, to: usize) -> &str {
+        core::str::from_utf8(&self.line.buf[from..to]).unwrap_or("")
+    }
+
+    /// Reprint everything from the cursor on, erase leftovers of a longer
+    /// line and return to the cursor.
+    fn redraw_tail(&self, out: &dyn ConsoleOps) {
+        out.puts(self.text(self.cursor, self.line.len));
+        out.puts("\x1b[K");
+        self.back(out, self.cursor, self.line.len);
+    }
+
+    fn insert(&mut self, out: &dyn ConsoleOps, c: char) {
+        let n = c.len_utf8();
+        if self.line.len + n > MAX_LINE {
+            out.putc('\x07');
+            return;
+        }
+
+        let mut i = self.line.len;
+        while i > self.cursor {
+            self.line.buf[i + n - 1] = self.line.buf[i - 1];
+            i -= 1;
+        }
+        c.encode_utf8(&mut self.line.buf[self.cursor..self.cursor + n]);
+        self.line.len += n;
+
+        out.putc(c);
+        self.cursor += n;
+        self.redraw_tail(out);
+    }
+
+    /// Remove `n` characters starting at the cursor.
+    fn remove(&mut self, n: usize) {
+        for i in self.cursor..self.line.len - n {
+            self.line.buf[i] = self.line.buf[i + n];
+        }
+        self.line.len -= n;
+    }
+
+    fn backspace(&mut self, out: &dyn ConsoleOps) {
+        if self.cursor == 0 {
+            return;
+        }
+
+        self.left(out);
+        self.delete(out);
+    }
+
+    fn delete(&mut self, out: &dyn ConsoleOps) {
+        if self.cursor == self.line.len {
+            return;
+        }
+
+        let n = self.char_len(self.cursor);
+        self.remove(n);
+        self.redraw_tail(out);
+    }
+
+    fn erase_to_start(&mut self, out: &dyn ConsoleOps) {
+        let n = self.cursor;
+
+        self.home(out);
+        self.remove(n);
+        self.redraw_tail(out);
+    }
+
+    fn left(&mut self, out: &dyn ConsoleOps) {
+        if let Some(c) = self.text(0, self.cursor).chars().next_back() {
+            self.cursor -= c.len_utf8();
+            out.putc('\x08');
+        }
+    }
+
+    fn right(&mut self, out: &dyn ConsoleOps) {
+        if let Some(c) = self.text(self.cursor, self.line.len).chars().next() {
+            out.putc(c);
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    fn home(&mut self, out: &dyn ConsoleOps) {
+        self.back(out, 0, self.cursor);
+        self.cursor = 0;
+    }
+
+    fn end(&mut self, out: &dyn ConsoleOps) {
+        out.puts(self.text(self.cursor, self.line.len));
+        self.cursor = self.line.len;
+    }
+
+    /// Clear the screen and draw prompt and line again.
+    fn redraw(&self, out: &dyn ConsoleOps) {
+        out.puts("\x1b[2J\x1b[H");
+        out.puts(self.prompt);
+        out.puts(self.text(0, self.line.len));
+        self.back(out, self.cursor, self.line.len);
+    }
+
+    /// Replace the shown line by `line`, with the cursor at its end.
+    fn show(&mut self, out: &dyn ConsoleOps, line: Line) {
+        self.home(out);
+        self.line = line;
+        self.redraw_tail(out);
+        self.end(out);
+    }
+
+    /// The slot of the entry `n` lines back, starting at 1.
+    fn history_slot(&self, n: usize) -> usize {
+        (self.history_next + HISTORY_LEN - n) % HISTORY_LEN
+    }
+
+    fn history_up(&mut self, out: &dyn ConsoleOps) {
+        if self.browsing == self.history_count {
+            return;
+        }
+
+        if self.browsing == 0 {
+            self.stash = self.line;
+        }
+        self.browsing += 1;
+
+        let line = self.history[self.history_slot(self.browsing)];
+        self.show(out, line);
+    }
+
+    fn history_down(&mut self, out: &dyn ConsoleOps) {
+        if self.browsing == 0 {
+            return;
+        }
+
+        self.browsing -= 1;
+
+        let line = if self.browsing == 0 {
+            self.stash
+        } else {
+            self.history[self.history_slot(self.browsing)]
+        };
+        self.show(out, line);
+    }
+
+    /// Add the completed line to the history, unless it is empty or repeats
+    /// the last one.
+    fn remember(&mut self) {
+        if self.line.len == 0 {
+            return;
+        }
+
+        if self.history_count > 0
+            && self.history[self.history_slot(1)].as_bytes() == self.line.as_bytes()
+        {
+            return;
+        }
+
+        self.history[self.history_next] = self.line;
+        self.history_next = (self.history_next + 1) % HISTORY_LEN;
+        self.history_count = (self.history_count + 1).min(HISTORY_LEN);
+    }
+}
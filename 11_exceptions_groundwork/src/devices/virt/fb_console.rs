@@ -0,0 +1,436 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A text console on the framebuffer.
+//!
+//! Characters are 8x16 cells, drawn with an 8x8 font that is stretched to
+//! twice its height. Understands newline, carriage return, backspace and tab,
+//! and the ANSI escape sequences for colors, cursor movement and erasing that
+//! the line editor and most programs use. Bytes that are not ASCII are drawn
+//! as a replacement glyph, once per UTF-8 sequence.
+
+use super::ConsoleOps;
+use crate::devices::hw::{Color, Framebuffer};
+use crate::sync::NullLock;
+
+mod font;
+
+const CELL_WIDTH: usize = font::WIDTH;
+const CELL_HEIGHT: usize = 2 * font::HEIGHT;
+
+const TAB_WIDTH: usize = 8;
+
+/// At most that many parameters of an escape sequence are kept.
+const MAX_PARAMS: usize = 4;
+
+/// The 16 ANSI colors, normal and bright.
+const PALETTE: [Color; 16] = [
+    Color::rgb(0x00, 0x00, 0x00),
+    Color::rgb(0xAA, 0x00, 0x00),
+    Color::rgb(0x00, 0xAA, 0x00),
+    Color::rgb(0xAA, 0x55, 0x00),
+    Color::rgb(0x00, 0x00, 0xAA),
+    Color::rgb(0xAA, 0x00, 0xAA),
+    Color::rgb(0x00, 0xAA, 0xAA),
+    Color::rgb(0xAA, 0xAA, 0xAA),
+    Color::rgb(0x55, 0x55, 0x55),
+    Color::rgb(0xFF, 0x55, 0x55),
+    Color::rgb(0x55, 0xFF, 0x55),
+    Color::rgb(0xFF, 0xFF, 0x55),
+    Color::rgb(0x55, 0x55, 0xFF),
+    Color::rgb(0xFF, 0x55, 0xFF),
+    Color::rgb(0x55, 0xFF, 0xFF),
+    Color::rgb(0xFF, 0xFF, 0xFF),
+];
+
+const DEFAULT_FG: usize = 7;
+const DEFAULT_BG: usize = 0;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Escape {
+    None,
+    /// After ESC
+    Start,
+    /// After ESC [
+    Csi,
+}
+
+struct State {
+    fb: Option<Framebuffer>,
+
+    cols: usize,
+    rows: usize,
+
+    /// The cursor. `col` may be `cols` after the last column was written,
+    /// the line wraps with the next character.
+    col: usize,
+    row: usize,
+    cursor_shown: bool,
+
+    /// Palette indices
+    fg: usize,
+    bg: usize,
+    bold: bool,
+
+    escape: Escape,
+    params: [usize; MAX_PARAMS],
+    num_params: usize,
+}
+
+/// The framebuffer text console.
+///
+/// Does nothing until a framebuffer is attached.
+pub struct FbConsole {
+    state: NullLock<State>,
+}
+
+impl FbConsole {
+    pub const fn new() -> FbConsole {
+        FbConsole {
+            state: NullLock::new(State {
+                fb: None,
+                cols: 0,
+                rows: 0,
+                col: 0,
+                row: 0,
+                cursor_shown: false,
+                fg: DEFAULT_FG,
+                bg: DEFAULT_BG,
+                bold: false,
+                escape: Escape::None,
+                params: [0; MAX_PARAMS],
+                num_params: 0,
+            }),
+        }
+    }
+
+    /// Take over `fb` and clear it. Returns the framebuffer that was attached
+    /// before, if any.
+    pub fn attach(&self, fb: Framebuffer) -> Option<Framebuffer> {
+        self.state.lock(|s| {
+            s.cols = fb.width() / CELL_WIDTH;
+            s.rows = fb.height() / CELL_HEIGHT;
+            let old = s.fb.replace(fb);
+
+            s.reset_attributes();
+            s.escape = Escape::None;
+            s.cursor_shown = false;
+            s.erase_display(2);
+            s.move_to(0, 0);
+            s.show_cursor();
+
+            old
+        })
+    }
+
+    /// Hand the framebuffer back, e.g. to draw on it directly or release it.
+    /// Output is dropped until one is attached again.
+    pub fn detach(&self) -> Option<Framebuffer> {
+        self.state.lock(|s| {
+            s.hide_cursor();
+            s.fb.take()
+        })
+    }
+
+    /// Columns and rows of text.
+    pub fn size(&self) -> (usize, usize) {
+        self.state.lock(|s| (s.cols, s.rows))
+    }
+}
+
+impl State {
+    fn reset_attributes(&mut self) {
+        self.fg = DEFAULT_FG;
+        self.bg = DEFAULT_BG;
+        self.bold = false;
+    }
+
+    fn fg_color(&self) -> Color {
+        // Bold brightens the normal colors, like on the Linux console.
+        if self.bold && self.fg < 8 {
+            PALETTE[self.fg + 8]
+        } else {
+            PALETTE[self.fg]
+        }
+    }
+
+    fn bg_color(&self) -> Color {
+        PALETTE[self.bg]
+    }
+
+    /// Invert the cell under the cursor.
+    fn toggle_cursor(&mut self) {
+        let x = self.col.min(self.cols.saturating_sub(1)) * CELL_WIDTH;
+        let y = self.row * CELL_HEIGHT;
+
+        if let Some(fb) = self.fb.as_mut() {
+            fb.invert_rect(x, y, CELL_WIDTH, CELL_HEIGHT);
+            self.cursor_shown = !self.cursor_shown;
+        }
+    }
+
+    fn show_cursor(&mut self) {
+        if !self.cursor_shown {
+            self.toggle_cursor();
+        }
+    }
+
+    fn hide_cursor(&mut self) {
+        if self.cursor_shown {
+            self.toggle_cursor();
+        }
+    }
+
+    /// Move the cursor, clamped to the screen.
+    fn move_to(&mut self, col: usize, row: usize) {
+        self.col = col.min(self.cols.saturating_sub(1));
+        self.row = row.min(self.rows.saturating_sub(1));
+    }
+
+    /// Fill cells of one row with the background color.
+    fn erase(&mut self, row: usize, from_col: usize, to_col: usize) {
+        let color = self.bg_color();
+
+        if let Some(fb) = self.fb.as_mut() {
+            fb.fill_rect(
+                from_col * CELL_WIDTH,
+                row * CELL_HEIGHT,
+                to_col.saturating_sub(from_col) * CELL_WIDTH,
+                CELL_HEIGHT,
+                color,
+            );
+        }
+    }
+
+    /// `ESC [ n K`: 0 erases to the end of the line, 1 to the cursor, 2 the
+    /// whole line.
+    fn erase_line(&mut self, mode: usize) {
+        let (row, col, cols) = (self.row, self.col, self.cols);
+
+        match mode {
+            0 => self.erase(row, col, cols),
+            1 => self.erase(row, 0, col + 1),
+            2 => self.erase(row, 0, cols),
+            _ => (),
+        }
+    }
+
+    /// `ESC [ n J`: 0 erases to the end of the screen, 1 to the cursor, 2
+    /// and 3 everything. The cursor stays where it is.
+    fn erase_display(&mut self, mode: usize) {
+        let (row, rows, cols) = (self.row, self.rows, self.cols);
+
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for r in row + 1..rows {
+                    self.erase(r, 0, cols);
+                }
+            }
+            1 => {
+                for r in 0..row {
+                    self.erase(r, 0, cols);
+                }
+                self.erase_line(1);
+            }
+            2 | 3 => {
+                // Also covers the pixels below the last full row.
+                let color = self.bg_color();
+                if let Some(fb) = self.fb.as_mut() {
+                    fb.clear(color);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Go to the next line, scrolling up at the bottom.
+    fn line_feed(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        let last = self.rows.saturating_sub(1);
+        if let Some(fb) = self.fb.as_mut() {
+            fb.copy_lines(CELL_HEIGHT, 0, last * CELL_HEIGHT);
+        }
+        self.erase(last, 0, self.cols);
+    }
+
+    /// Draw `c` at the cursor and advance it.
+    fn draw(&mut self, c: u8) {
+        if self.col >= self.cols {
+            self.col = 0;
+            self.line_feed();
+        }
+
+        let fg = self.fg_color();
+        let bg = self.bg_color();
+        let (x, y) = (self.col * CELL_WIDTH, self.row * CELL_HEIGHT);
+
+        if let Some(fb) = self.fb.as_mut() {
+            let (fg, bg) = (fb.pixel_value(fg), fb.pixel_value(bg));
+            let glyph = font::glyph(c);
+            let mut pixels = [0; CELL_WIDTH * CELL_HEIGHT];
+
+            // Every line of the glyph is drawn twice.
+            for (i, line) in pixels.chunks_mut(CELL_WIDTH).enumerate() {
+                let bits = glyph[i / 2];
+
+                for (col, pixel) in line.iter_mut().enumerate() {
+                    *pixel = if bits & (0x80 >> col) != 0 { fg } else { bg };
+                }
+            }
+
+            fb.blit(x, y, CELL_WIDTH, &pixels);
+        }
+
+        self.col += 1;
+    }
+
+    /// `ESC [ ... m`
+    fn select_graphic_rendition(&mut self) {
+        // No parameters is the same as 0.
+        let num = self.num_params.max(1).min(MAX_PARAMS);
+
+        for i in 0..num {
+            match self.params[i] {
+                0 => self.reset_attributes(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                p @ 30..=37 => self.fg = p - 30,
+                39 => self.fg = DEFAULT_FG,
+                p @ 40..=47 => self.bg = p - 40,
+                49 => self.bg = DEFAULT_BG,
+                p @ 90..=97 => self.fg = p - 90 + 8,
+                p @ 100..=107 => self.bg = p - 100 + 8,
+                _ => (),
+            }
+        }
+    }
+
+    /// Execute a control sequence that ended with `c`.
+    fn csi(&mut self, c: u8) {
+        // Parameter `i`, or `default` if it is missing or 0.
+        let param = |s: &State, i: usize, default: usize| match s.params[i] {
+            0 => default,
+            p => p,
+        };
+        let n = param(self, 0, 1);
+
+        match c {
+            b'A' => self.move_to(self.col, self.row.saturating_sub(n)),
+            b'B' => self.move_to(self.col, self.row + n),
+            b'C' => self.move_to(self.col + n, self.row),
+            b'D' => self.move_to(self.col.saturating_sub(n), self.row),
+            b'H' | b'f' => {
+                let col = param(self, 1, 1);
+                self.move_to(col - 1, n - 1);
+            }
+            b'J' => self.erase_display(self.params[0]),
+            b'K' => self.erase_line(self.params[0]),
+            b'm' => self.select_graphic_rendition(),
+            _ => (),
+        }
+    }
+
+    fn write_byte(&mut self, b: u8) {
+        match self.escape {
+            Escape::None => (),
+            Escape::Start => {
+                if b == b'[' {
+                    self.escape = Escape::Csi;
+                    self.params = [0; MAX_PARAMS];
+                    self.num_params = 0;
+                } else {
+                    self.escape = Escape::None;
+                }
+                return;
+            }
+            Escape::Csi => {
+                match b {
+                    b'0'..=b'9' => {
+                        if self.num_params == 0 {
+                            self.num_params = 1;
+                        }
+                        if let Some(p) = self.params.get_mut(self.num_params - 1) {
+                            *p = p.saturating_mul(10).saturating_add(usize::from(b - b'0'));
+                        }
+                    }
+                    b';' => self.num_params = (self.num_params.max(1) + 1).min(MAX_PARAMS + 1),
+                    0x40..=0x7E => {
+                        self.csi(b);
+                        self.escape = Escape::None;
+                    }
+                    // Private markers and intermediate bytes
+                    _ => (),
+                }
+                return;
+            }
+        }
+
+        match b {
+            0x1B => self.escape = Escape::Start,
+            b'\n' => {
+                self.col = 0;
+                self.line_feed();
+            }
+            b'\r' => self.col = 0,
+            b'\t' => {
+                let next = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.col = next.min(self.cols.saturating_sub(1));
+            }
+            0x08 => self.col = self.col.min(self.cols.saturating_sub(1)).saturating_sub(1),
+            0x20..=0x7E => self.draw(b),
+            // UTF-8 continuation bytes
+            0x80..=0xBF => (),
+            // Start of a character that the font does not have
+            0xC0..=0xFF => self.draw(0),
+            // Other control characters, e.g. the bell
+            _ => (),
+        }
+    }
+}
+
+impl ConsoleOps for FbConsole {
+    fn write_byte(&self, b: u8) {
+        self.state.lock(|s| {
+            s.hide_cursor();
+            s.write_byte(b);
+            s.show_cursor();
+        });
+    }
+
+    /// Same as the default, but only draws the cursor once.
+    fn puts(&self, string: &str) {
+        self.state.lock(|s| {
+            s.hide_cursor();
+            for b in string.bytes() {
+                s.write_byte(b);
+            }
+            s.show_cursor();
+        });
+    }
+}
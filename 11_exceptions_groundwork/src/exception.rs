@@ -22,6 +22,7 @@
  * SOFTWARE.
  */
 
+use crate::memory::kernel_stack;
 use crate::println;
 use cortex_a::{barrier, regs::*};
 
@@ -51,6 +52,85 @@ pub struct ExceptionContext {
     gpr: GPR,
     spsr_el1: u64,
     elr_el1: u64,
+
+    // The stack pointer of the interrupted code. Recorded only, changing it
+    // has no effect on the return.
+    sp: u64,
+}
+
+/// Exception Syndrome Register, EL1.
+fn esr_el1() -> u64 {
+    let esr;
+    unsafe { asm!("mrs $0, ESR_EL1" : "=r"(esr) ::: "volatile") };
+    esr
+}
+
+/// Fault Address Register, EL1.
+fn far_el1() -> u64 {
+    let far;
+    unsafe { asm!("mrs $0, FAR_EL1" : "=r"(far) ::: "volatile") };
+    far
+}
+
+/// Exception classes of ESR_EL1.EC that are of interest.
+mod exception_class {
+    pub const DATA_ABORT_LOWER_EL: u64 = 0b10_0100;
+    pub const DATA_ABORT_CURRENT_EL: u64 = 0b10_0101;
+}
+
+fn exception_class() -> u64 {
+    (esr_el1() >> 26) & 0x3F
+}
+
+/// Try to resolve a data abort in user memory, e.g. by backing a page of an
+/// anonymous mapping or by copying a copy-on-write page. Returns true if the
+/// faulting instruction can be retried.
+fn handle_user_data_abort() -> bool {
+    use crate::memory::mmu::{self, FaultKind};
+
+    const ISS_WNR: u64 = 1 << 6;
+    const ISS_DFSC_MASK: u64 = 0x3F;
+
+    let esr = esr_el1();
+    let kind = match esr & ISS_DFSC_MASK {
+        0b00_0100..=0b00_0111 => FaultKind::Translation,
+        0b00_1100..=0b00_1111 => FaultKind::Permission,
+        _ => return false,
+    };
+
+    mmu::handle_fault(far_el1() as usize, kind, esr & ISS_WNR != 0).is_ok()
+}
+
+fn core_id() -> u64 {
+    const CORE_MASK: u64 = 0x3;
+
+    MPIDR_EL1.get() & CORE_MASK
+}
+
+/// Print a stack overflow report and halt the core. `addr` is the address in a
+/// guard page that was, or would have been, accessed.
+fn report_stack_overflow(addr: usize, sp: u64) -> ! {
+    let kind = match kernel_stack::guard_page_owner(addr) {
+        Some((kernel_stack::Kind::Exception, _)) => "kernel exception stack",
+        _ => "kernel stack",
+    };
+
+    println!("[!] {} overflow on core {}", kind, core_id());
+    println!("      SP:      {:#010X}", sp);
+    println!("      Address: {:#010X}", addr);
+    println!("      Halting CPU.");
+
+    loop {
+        cortex_a::asm::wfe()
+    }
+}
+
+/// Called from the vector code if the context of an exception could not be
+/// saved at `context_addr` because the stack ran into a guard page. Runs on
+/// the exception stack of the core.
+#[no_mangle]
+unsafe extern "C" fn kernel_stack_overflow(sp: u64, context_addr: u64) -> ! {
+    report_stack_overflow(context_addr as usize, sp)
 }
 
 /// The default exception, invoked for every exception type unless the handler
@@ -84,8 +164,26 @@ unsafe extern "C" fn default_exception_handler() {
 // unsafe extern "C" fn lower_aarch32_irq(e: &mut ExceptionContext);
 // unsafe extern "C" fn lower_aarch32_serror(e: &mut ExceptionContext);
 
+/// Check if a synchronous exception is a data abort in a stack guard page, next
+/// to the interrupted `sp`.
+fn is_stack_overflow(sp: u64) -> bool {
+    exception_class() == exception_class::DATA_ABORT_CURRENT_EL
+        && kernel_stack::overflow_owner(far_el1() as usize, sp as usize).is_some()
+}
+
+/// The kernel runs on SP_EL0, so this is a synchronous exception of kernel
+/// code, handled on the exception stack.
 #[no_mangle]
-unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
+unsafe extern "C" fn current_el0_synchronous(e: &mut ExceptionContext) {
+    if is_stack_overflow(e.sp) {
+        report_stack_overflow(far_el1() as usize, e.sp);
+    }
+
+    // The kernel touching lazily backed user memory.
+    if exception_class() == exception_class::DATA_ABORT_CURRENT_EL && handle_user_data_abort() {
+        return;
+    }
+
     println!("[!] A synchronous exception happened.");
     println!("      ELR_EL1: {:#010X}", e.elr_el1);
     println!(
@@ -98,3 +196,54 @@ unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
     println!("      ELR_EL1 modified: {:#010X}", e.elr_el1);
     println!("      Returning from exception...\n");
 }
+
+/// A synchronous exception while already on the exception stack, aka in an
+/// exception handler. There is no recovering from this.
+#[no_mangle]
+unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
+    if is_stack_overflow(e.sp) {
+        report_stack_overflow(far_el1() as usize, e.sp);
+    }
+
+    println!("[!] A synchronous exception happened in an exception handler.");
+    println!("      ELR_EL1: {:#010X}", e.elr_el1);
+    println!("      SP:      {:#010X}", e.sp);
+    println!("      ESR_EL1: {:#010X}", esr_el1());
+    println!("      FAR_EL1: {:#010X}", far_el1());
+    println!("      Halting CPU.");
+
+    loop {
+        cortex_a::asm::wfe()
+    }
+}
+
+/// A synchronous exception of user code, e.g. a page fault.
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) {
+    if exception_class() == exception_class::DATA_ABORT_LOWER_EL && handle_user_data_abort() {
+        return;
+    }
+
+    println!("[!] Unhandled synchronous exception in EL0.");
+    println!("      ELR_EL1: {:#010X}", e.elr_el1);
+    println!("      SP:      {:#010X}", e.sp);
+    println!("      ESR_EL1: {:#010X}", esr_el1());
+    println!("      FAR_EL1: {:#010X}", far_el1());
+    println!("      Halting CPU.");
+
+    loop {
+        cortex_a::asm::wfe()
+    }
+}
+
+/// An IRQ while the kernel was running.
+#[no_mangle]
+unsafe extern "C" fn current_el0_irq(_e: &mut ExceptionContext) {
+    crate::interrupt::dispatch();
+}
+
+/// An IRQ while user code was running.
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_irq(_e: &mut ExceptionContext) {
+    crate::interrupt::dispatch();
+}
@@ -0,0 +1,111 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Accounting of the time the CPU spends waiting, and work that interrupt
+//! handlers leave for thread context.
+
+use crate::{interrupt, sync::IrqSafeNullLock, timer};
+use cortex_a::asm;
+
+/// Microseconds spent in `wait_until()` since boot.
+static IDLE_US: IrqSafeNullLock<u64> = IrqSafeNullLock::new(0);
+
+/// Most pieces of work that can be deferred at the same time.
+const MAX_DEFERRED: usize = 4;
+
+static DEFERRED: IrqSafeNullLock<[Option<fn()>; MAX_DEFERRED]> =
+    IrqSafeNullLock::new([None; MAX_DEFERRED]);
+
+/// Wait until `ready()` returns true, and count the time as idle.
+///
+/// If IRQs are unmasked, the core sleeps until the next interrupt between two
+/// checks. Otherwise, it spins.
+pub fn wait_until<F>(mut ready: F)
+where
+    F: FnMut() -> bool,
+{
+    if ready() {
+        return;
+    }
+
+    let start = timer::uptime_us();
+    if interrupt::local_irqs_enabled() {
+        loop {
+            // Check with IRQs masked, so that an interrupt that makes us
+            // ready can not slip in between the check and the wfi. A pending
+            // IRQ wakes up wfi even when masked, and is taken on restore.
+            let daif = interrupt::local_irq_save();
+            if ready() {
+                interrupt::local_irq_restore(daif);
+                break;
+            }
+
+            unsafe { asm!("wfi" :::: "volatile") };
+            interrupt::local_irq_restore(daif);
+        }
+    } else {
+        while !ready() {
+            asm::nop();
+        }
+    }
+    let elapsed = timer::uptime_us() - start;
+
+    IDLE_US.lock(|i| *i += elapsed);
+}
+
+/// Microseconds the CPU was idle since boot.
+pub fn idle_us() -> u64 {
+    IDLE_US.lock(|i| *i)
+}
+
+/// Let `work` run in thread context, the next time the kernel waits for
+/// input. For interrupt handlers with work that must not run with IRQs
+/// masked, like mailbox calls. Work that is already pending is not added
+/// again.
+pub fn defer(work: fn()) -> Result<(), &'static str> {
+    DEFERRED.lock(|d| {
+        if d.iter().any(|w| *w == Some(work)) {
+            return Ok(());
+        }
+
+        match d.iter_mut().find(|w| w.is_none()) {
+            Some(slot) => {
+                *slot = Some(work);
+                Ok(())
+            }
+            None => Err("No free slot for deferred work."),
+        }
+    })
+}
+
+pub fn has_deferred() -> bool {
+    DEFERRED.lock(|d| d.iter().any(|w| w.is_some()))
+}
+
+/// Run the deferred work. Must be called without any lock held.
+pub fn run_deferred() {
+    while let Some(work) = DEFERRED.lock(|d| d.iter_mut().find_map(|w| w.take())) {
+        work();
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! IRQ handling.
+//!
+//! Drivers register a handler for the GPU peripheral interrupt they own. The
+//! handler gets the context value that was passed on registration, e.g. a
+//! channel number or the base address of the device.
+
+use crate::devices::hw;
+use crate::{memory::map, sync::NullLock};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const NUM_IRQS: usize = hw::InterruptController::NUM_IRQS;
+
+#[derive(Copy, Clone)]
+struct Handler {
+    func: fn(usize),
+    context: usize,
+}
+
+static CONTROLLER: hw::InterruptController =
+    hw::InterruptController::new(map::physical::IRQ_CTRL_BASE);
+
+static HANDLERS: NullLock<[Option<Handler>; NUM_IRQS]> = NullLock::new([None; NUM_IRQS]);
+
+/// Set while `dispatch()` runs the handlers.
+static IN_IRQ: AtomicBool = AtomicBool::new(false);
+
+/// Mask IRQs on the executing core and return the previous mask state.
+pub fn local_irq_save() -> u64 {
+    let daif;
+    unsafe {
+        asm!("mrs $0, DAIF" : "=r"(daif) ::: "volatile");
+        asm!("msr DAIFSet, #2" :::: "volatile");
+    }
+
+    daif
+}
+
+/// Restore the mask state returned by `local_irq_save()`.
+pub fn local_irq_restore(daif: u64) {
+    unsafe { asm!("msr DAIF, $0" :: "r"(daif) :: "volatile") };
+}
+
+/// Whether IRQs are unmasked on the executing core.
+pub fn local_irqs_enabled() -> bool {
+    let daif: u64;
+    unsafe { asm!("mrs $0, DAIF" : "=r"(daif) ::: "volatile") };
+
+    // DAIF.I
+    daif & (1 << 7) == 0
+}
+
+/// Unmask IRQs on the executing core.
+pub fn local_irq_enable() {
+    unsafe { asm!("msr DAIFClr, #2" :::: "volatile") };
+}
+
+/// Mask all interrupts at the controller.
+pub fn init() {
+    CONTROLLER.init();
+}
+
+/// Install `func` as the handler of `irq` and enable the interrupt.
+pub fn register(irq: usize, func: fn(usize), context: usize) -> Result<(), &'static str> {
+    if irq >= NUM_IRQS {
+        return Err("IRQ number out of range.");
+    }
+
+    let daif = local_irq_save();
+    let ret = HANDLERS.lock(|h| {
+        if h[irq].is_some() {
+            return Err("IRQ already has a handler.");
+        }
+
+        h[irq] = Some(Handler { func, context });
+        CONTROLLER.enable(irq);
+
+        Ok(())
+    });
+    local_irq_restore(daif);
+
+    ret
+}
+
+/// Disable `irq` and remove its handler.
+pub fn unregister(irq: usize) {
+    if irq >= NUM_IRQS {
+        return;
+    }
+
+    let daif = local_irq_save();
+    CONTROLLER.disable(irq);
+    HANDLERS.lock(|h| h[irq] = None);
+    local_irq_restore(daif);
+}
+
+/// Whether the executing code runs from an IRQ handler.
+pub fn in_irq() -> bool {
+    IN_IRQ.load(Ordering::Relaxed)
+}
+
+/// Call the handlers of all pending interrupts. Runs with IRQs masked.
+pub fn dispatch() {
+    let mut pending = CONTROLLER.pending();
+
+    IN_IRQ.store(true, Ordering::Relaxed);
+
+    while pending != 0 {
+        let irq = pending.trailing_zeros() as usize;
+        pending &= !(1 << irq);
+
+        match HANDLERS.lock(|h| h[irq]) {
+            Some(handler) => (handler.func)(handler.context),
+
+            // Nobody will acknowledge it, so keep it from firing forever.
+            None => CONTROLLER.disable(irq),
+        }
+    }
+
+    IN_IRQ.store(false, Ordering::Relaxed);
+}
@@ -0,0 +1,373 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The kernel log.
+//!
+//! The `error!`, `warn!`, `info!`, `debug!` and `trace!` macros take the same
+//! arguments as their counterparts in the `log` crate. Records are
+//! timestamped, tagged with the core that wrote them and kept in a ring
+//! buffer, so that nothing is lost before the console is up. The buffer is
+//! replayed once it is, and can be printed again with `dmesg()`.
+//!
+//! The console is not IRQ safe, so records logged from an IRQ handler are
+//! only put into the buffer. They are printed by the next print or log call
+//! from thread context.
+
+use crate::{devices::virt::ConsoleOps, interrupt, print, sync::IrqSafeNullLock, timer};
+use core::fmt::{self, Write};
+use cortex_a::regs::*;
+
+/// Verbosity levels, from most to least severe.
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Size of the ring buffer. The oldest records are dropped when it is full.
+const BUF_SIZE: usize = 16 * 1024;
+
+/// Longest record, including the header. Longer ones are cut.
+const MAX_RECORD: usize = 256;
+
+const MAX_FILTERS: usize = 8;
+const MAX_MODULE_NAME: usize = 32;
+
+/// A level for a module and its submodules, e.g. "memory" or
+/// "devices::hw::dma".
+#[derive(Copy, Clone)]
+struct Filter {
+    module: [u8; MAX_MODULE_NAME],
+    module_len: usize,
+    level: Level,
+}
+
+impl Filter {
+    fn module(&self) -> &str {
+        core::str::from_utf8(&self.module[..self.module_len]).unwrap_or("")
+    }
+
+    fn matches(&self, module: &str) -> bool {
+        let m = self.module();
+
+        module.starts_with(m) && (module.len() == m.len() || module[m.len()..].starts_with("::"))
+    }
+}
+
+struct Log {
+    buf: [u8; BUF_SIZE],
+    head: usize,
+    len: usize,
+
+    /// Bytes ever written. Positions in the log count from the first byte
+    /// ever written, so that they stay valid when old records are dropped.
+    written: usize,
+
+    /// Up to where the console got the log.
+    printed: usize,
+
+    default_level: Level,
+    filters: [Option<Filter>; MAX_FILTERS],
+}
+
+static LOG: IrqSafeNullLock<Log> = IrqSafeNullLock::new(Log {
+    buf: [0; BUF_SIZE],
+    head: 0,
+    len: 0,
+    written: 0,
+    printed: 0,
+    default_level: Level::Info,
+    filters: [None; MAX_FILTERS],
+});
+
+/// A single record. Cuts what does not fit.
+struct Record {
+    buf: [u8; MAX_RECORD],
+    len: usize,
+}
+
+impl Record {
+    const fn new() -> Record {
+        Record {
+            buf: [0; MAX_RECORD],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            // Keep room for the newline, and never cut a character in half.
+            if self.len + c.len_utf8() >= MAX_RECORD {
+                break;
+            }
+
+            self.len += c.encode_utf8(&mut self.buf[self.len..]).len();
+        }
+
+        Ok(())
+    }
+}
+
+impl Log {
+    /// The level of the most specific filter for `module`.
+    fn level(&self, module: &str) -> Level {
+        self.filters
+            .iter()
+            .filter_map(|f| *f)
+            .filter(|f| f.matches(module))
+            .max_by_key(|f| f.module_len)
+            .map_or(self.default_level, |f| f.level)
+    }
+
+    /// Position of the oldest byte still in the buffer.
+    fn start(&self) -> usize {
+        self.written - self.len
+    }
+
+    fn byte(&self, pos: usize) -> u8 {
+        self.buf[(self.head + pos - self.start()) % BUF_SIZE]
+    }
+
+    /// Drop the oldest record.
+    fn drop_oldest(&mut self) {
+        while self.len > 0 {
+            let c = self.buf[self.head];
+
+            self.head = (self.head + 1) % BUF_SIZE;
+            self.len -= 1;
+
+            if c == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn push(&mut self, record: &Record) {
+        while BUF_SIZE - self.len < record.len {
+            self.drop_oldest();
+        }
+
+        for b in record.buf[..record.len].iter() {
+            self.buf[(self.head + self.len) % BUF_SIZE] = *b;
+            self.len += 1;
+        }
+        self.written += record.len;
+    }
+
+    /// Copy the record at `pos` into `record`, or the oldest one if it was
+    /// dropped in the meantime. Returns the position of the next record.
+    fn read(&self, pos: usize, record: &mut Record) -> Option<usize> {
+        let mut pos = pos.max(self.start());
+
+        if pos >= self.written {
+            return None;
+        }
+
+        while pos < self.written && record.len < MAX_RECORD {
+            let c = self.byte(pos);
+
+            record.buf[record.len] = c;
+            record.len += 1;
+            pos += 1;
+
+            if c == b'\n' {
+                break;
+            }
+        }
+
+        Some(pos)
+    }
+}
+
+/// The module path without the crate name.
+fn module_name(path: &'static str) -> &'static str {
+    path.splitn(2, "::").nth(1).unwrap_or(path)
+}
+
+fn core_id() -> u64 {
+    const CORE_MASK: u64 = 0x3;
+
+    MPIDR_EL1.get() & CORE_MASK
+}
+
+/// Print what did not make it to the console yet, if there is a console and
+/// we are not in an IRQ handler.
+///
+/// Goes record by record, so that the log is not locked while printing.
+/// Writes to the console directly, because `print!` drains the log itself.
+fn flush() {
+    if interrupt::in_irq() || !crate::CONSOLE.lock(|c| c.has_outputs()) {
+        return;
+    }
+
+    loop {
+        let mut record = Record::new();
+        let got = LOG.lock(|l| match l.read(l.printed, &mut record) {
+            Some(next) => {
+                l.printed = next;
+                true
+            }
+            None => false,
+        });
+
+        if !got {
+            break;
+        }
+
+        crate::CONSOLE.lock(|c| {
+            let _ = c.write_str(record.as_str());
+        });
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, path: &'static str, args: fmt::Arguments) {
+    let module = module_name(path);
+
+    if level > LOG.lock(|l| l.level(module)) {
+        return;
+    }
+
+    let us = timer::uptime_us();
+    let mut record = Record::new();
+    let _ = write!(
+        record,
+        "[{:>5}.{:06}] c{} {:<5} {}: ",
+        us / 1_000_000,
+        us % 1_000_000,
+        core_id(),
+        level.name(),
+        module
+    );
+    let _ = record.write_fmt(args);
+    record.buf[record.len] = b'\n';
+    record.len += 1;
+
+    LOG.lock(|l| l.push(&record));
+    flush();
+}
+
+/// Print the records that were logged while there was no console, or from
+/// an IRQ handler.
+pub fn replay() {
+    flush();
+}
+
+/// Call `f` with every record in the buffer, oldest first.
+fn for_each_record<F: FnMut(&str)>(mut f: F) {
+    let mut pos = 0;
+
+    loop {
+        let mut record = Record::new();
+
+        match LOG.lock(|l| l.read(pos, &mut record)) {
+            Some(next) => pos = next,
+            None => break,
+        }
+
+        f(record.as_str());
+    }
+}
+
+/// Print the whole buffer.
+pub fn dmesg() {
+    for_each_record(|r| print!("{}", r));
+}
+
+/// Write the whole buffer to `out` only, e.g. a console output that was just
+/// added and missed the boot.
+pub fn dmesg_to(out: &dyn ConsoleOps) {
+    for_each_record(|r| out.puts(r));
+}
+
+/// The level of modules without a filter.
+pub fn default_level() -> Level {
+    LOG.lock(|l| l.default_level)
+}
+
+pub fn set_default_level(level: Level) {
+    LOG.lock(|l| l.default_level = level);
+}
+
+/// Set the level of `module` and its submodules, given without the crate
+/// name, e.g. "memory".
+pub fn set_level(module: &str, level: Level) -> Result<(), &'static str> {
+    if module.is_empty() || module.len() > MAX_MODULE_NAME {
+        return Err("Invalid module name.");
+    }
+
+    LOG.lock(|l| {
+        let slot = match l
+            .filters
+            .iter()
+            .position(|f| f.map_or(false, |f| f.module() == module))
+        {
+            Some(i) => i,
+            None => l
+                .filters
+                .iter()
+                .position(|f| f.is_none())
+                .ok_or("No free log filter slot.")?,
+        };
+
+        let mut filter = Filter {
+            module: [0; MAX_MODULE_NAME],
+            module_len: module.len(),
+            level,
+        };
+        filter.module[..module.len()].copy_from_slice(module.as_bytes());
+        l.filters[slot] = Some(filter);
+
+        Ok(())
+    })
+}
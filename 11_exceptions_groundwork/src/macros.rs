@@ -39,10 +39,63 @@ macro_rules! println {
     })
 }
 
+/// Log a record at `level`. Same syntax as in the `log` crate, e.g.
+/// `log!(Level::Info, "{} MHz", hz)` or with an explicit `target:`.
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $lvl:expr, $($arg:tt)+) => (
+        $crate::log::_log($lvl, $target, format_args!($($arg)+))
+    );
+    ($lvl:expr, $($arg:tt)+) => ($crate::log!(target: module_path!(), $lvl, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! error {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Error, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Warn, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Info, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Debug, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Trace, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
+    // Keep the order with records that were logged from IRQ handlers.
+    crate::log::replay();
+
     crate::CONSOLE.lock(|c| {
         c.write_fmt(args).unwrap();
     })
@@ -25,6 +25,7 @@
 #![no_std]
 #![no_main]
 #![feature(allocator_api)]
+#![feature(asm)]
 #![feature(const_fn)]
 #![feature(custom_attribute)]
 #![feature(format_args_nl)]
@@ -32,26 +33,136 @@
 #![feature(label_break_value)]
 #![feature(range_contains)]
 
+mod board;
+mod clocks;
 mod delays;
 mod devices;
 mod exception;
+mod idle;
+mod interrupt;
+mod log;
 mod macros;
 mod memory;
+mod power;
+mod ring_buffer;
+mod shell;
 mod sync;
+mod thermal;
+mod timer;
 
 /// The global console. Output of the print! and println! macros.
 static CONSOLE: sync::NullLock<devices::virt::Console> =
     sync::NullLock::new(devices::virt::Console::new());
 
+/// The UARTs. They live in statics, so that the console can refer to them.
+static MINI_UART: devices::hw::MiniUart =
+    devices::hw::MiniUart::new(memory::map::physical::MINI_UART_BASE);
+static PL011_UART: devices::hw::PL011Uart =
+    devices::hw::PL011Uart::new(memory::map::physical::PL011_UART_BASE);
+
 /// The global allocator for DMA-able memory. That is, memory which is tagged
 /// non-cacheable in the page tables.
-static DMA_ALLOCATOR: sync::NullLock<memory::BumpAllocator> =
-    sync::NullLock::new(memory::BumpAllocator::new(
+static DMA_ALLOCATOR: sync::NullLock<memory::FreeListAllocator> =
+    sync::NullLock::new(memory::FreeListAllocator::new(
         memory::map::virt::DMA_HEAP_START as usize,
         memory::map::virt::DMA_HEAP_END as usize,
         "Global DMA Allocator",
     ));
 
+/// The allocator for DMA buffers in cacheable memory. Users must maintain the
+/// caches around DMA transfers.
+static CACHED_DMA_ALLOCATOR: sync::NullLock<memory::FreeListAllocator> =
+    sync::NullLock::new(memory::FreeListAllocator::new(
+        memory::map::virt::CACHED_DMA_HEAP_START as usize,
+        memory::map::virt::CACHED_DMA_HEAP_END as usize,
+        "Cached DMA Allocator",
+    ));
+
+/// The Videocore mailbox, once it is set up. Only used in thread context,
+/// interrupt handlers defer their mailbox calls.
+static MBOX: sync::NullLock<Option<devices::hw::VideocoreMbox>> = sync::NullLock::new(None);
+
+/// The DMA controller. Shared with its completion interrupt handler.
+static DMA: sync::IrqSafeNullLock<devices::hw::Dma> =
+    sync::IrqSafeNullLock::new(devices::hw::Dma::new(memory::map::physical::DMA_BASE));
+
+/// Set to the channel index by the completion callback of the DMA 2D demo.
+static DMA_2D_DONE: sync::IrqSafeNullLock<Option<usize>> = sync::IrqSafeNullLock::new(None);
+
+/// Mirrors the console to HDMI, once the framebuffer is set up.
+static FB_CONSOLE: devices::virt::FbConsole = devices::virt::FbConsole::new();
+
+/// The global allocator for page frames, used for page tables and user memory.
+static FRAME_ALLOCATOR: sync::NullLock<memory::FrameAllocator> = sync::NullLock::new(
+    memory::FrameAllocator::new(memory::map::virt::PAGE_POOL_START as usize),
+);
+
+/// The board resets if the kernel does not serve the timer interrupt for this
+/// long.
+const WATCHDOG_TIMEOUT_MS: u64 = 10_000;
+
+/// Resolution of the HDMI output.
+const FB_WIDTH: u32 = 640;
+const FB_HEIGHT: u32 = 480;
+
+fn dma_2d_done(channel: usize) {
+    DMA_2D_DONE.lock(|d| *d = Some(channel));
+}
+
+/// Let a full DMA channel cut the middle 8x8 words out of a 16x16 image with
+/// a 2D transfer. Returns whether the copy is right and the completion
+/// callback ran.
+fn dma_2d_demo() -> Result<bool, devices::hw::DmaError> {
+    use devices::hw::{Chain, ControlBlock, DmaError};
+    use memory::{
+        cache::{self, DmaSync},
+        dma::{DmaSlice, Heap},
+    };
+
+    const SIDE: usize = 16;
+    const RECT: usize = 8;
+    const OFFSET: usize = (SIDE - RECT) / 2;
+
+    // Cacheable buffers, which need syncing around the transfer. Whole cache
+    // lines, so that invalidating `dest` does not hit other data.
+    let line = cache::dcache_line_size();
+    let mut src = DmaSlice::<u32>::new_zeroed_in(Heap::Cached, SIDE * SIDE, line)
+        .map_err(|_| DmaError::OutOfMemory)?;
+    for (i, x) in src.iter_mut().enumerate() {
+        *x = i as u32;
+    }
+    let dest = DmaSlice::<u32>::new_zeroed_in(Heap::Cached, RECT * RECT, line)
+        .map_err(|_| DmaError::OutOfMemory)?;
+
+    let mut channel = DMA.lock(|d| d.alloc_channel(true))?;
+    DMA.lock(|d| d.set_completion_handler(&channel, dma_2d_done, channel.index()));
+
+    // After each row, skip the words left and right of the rectangle in the
+    // source. The destination is packed.
+    let first = src.bus_addr() + ((OFFSET * SIDE + OFFSET) * 4) as u32;
+    let row_len = (RECT * 4) as u16;
+    let src_stride = ((SIDE - RECT) * 4) as i16;
+    let cb = ControlBlock::new(dest.bus_addr(), first, 0)
+        .two_d(row_len, RECT as u16, src_stride, 0)
+        .interrupt();
+
+    let mut chain = Chain::new(1)?;
+    chain.push(cb)?;
+
+    src.sync_for_device();
+    dest.sync_for_device();
+    channel.run(&chain)?;
+    unsafe { dest.sync_for_cpu() };
+
+    let done = DMA_2D_DONE.lock(|d| d.take()) == Some(channel.index());
+    let correct = dest
+        .iter()
+        .enumerate()
+        .all(|(i, x)| *x as usize == (OFFSET + i / RECT) * SIDE + OFFSET + i % RECT);
+
+    Ok(done && correct)
+}
+
 fn kernel_entry() -> ! {
     use devices::hw;
     use devices::virt::ConsoleOps;
@@ -65,18 +176,32 @@ fn kernel_entry() -> ! {
     //------------------------------------------------------------
     let gpio = hw::GPIO::new(memory::map::physical::GPIO_BASE);
 
+    // Mask all interrupts at the controller. Drivers enable theirs during
+    // init, starting with the MiniUart, and they are taken once IRQs are
+    // unmasked further below.
+    interrupt::init();
+
     //------------------------------------------------------------
     // Instantiate MiniUart
     //------------------------------------------------------------
-    let mini_uart = hw::MiniUart::new(memory::map::physical::MINI_UART_BASE);
-    mini_uart.init(&gpio);
+    let serial_config = hw::SerialConfig::default();
+    let mut mini_uart_output = None;
 
-    CONSOLE.lock(|c| {
-        // Moves mini_uart into the global CONSOLE. It is not accessible anymore
-        // for the remaining parts of kernel_entry().
-        c.replace_with(mini_uart.into());
-    });
-    println!("\n[0] MiniUart online.");
+    // 115200 8N1 always works at the default core clock. If it does not,
+    // the failure is kept in the log until there is a console.
+    match MINI_UART.init(&gpio, &serial_config, hw::MiniUart::DEFAULT_CORE_CLOCK) {
+        Ok(()) => {
+            CONSOLE.lock(|c| {
+                mini_uart_output = c.add_output(&MINI_UART).ok();
+                c.set_input(Some(&MINI_UART));
+            });
+            println!("\n[0] MiniUart online.");
+        }
+        Err(e) => error!("[0] MiniUart init failed: {:?}", e),
+    }
+
+    // Anything that was logged while there was no console.
+    log::replay();
 
     //------------------------------------------------------------
     // Greet the user
@@ -95,7 +220,7 @@ fn kernel_entry() -> ! {
         // Bring up memory subsystem
         //------------------------------------------------------------
         if unsafe { memory::mmu::init() }.is_err() {
-            println!("[2][Error] Could not set up MMU. Aborting.");
+            error!("[2] Could not set up MMU. Aborting.");
             break 'init;
         };
         println!("[2] MMU online.");
@@ -113,15 +238,42 @@ fn kernel_entry() -> ! {
             }
 
             Err(_) => {
-                println!("[3][Error] Could not set up Videocore Mailbox. Aborting.");
+                error!("[3] Could not set up Videocore Mailbox. Aborting.");
                 break 'init;
             }
         }
 
+        // Show the console on the framebuffer as early as possible, with what
+        // was logged so far.
+        match hw::Framebuffer::new(&mut v_mbox, FB_WIDTH, FB_HEIGHT, false) {
+            Ok(fb) => {
+                FB_CONSOLE.attach(fb);
+                log::dmesg_to(&FB_CONSOLE);
+
+                match CONSOLE.lock(|c| c.add_output(&FB_CONSOLE)) {
+                    Ok(_) => println!("[3] Framebuffer console {}x{} online.", FB_WIDTH, FB_HEIGHT),
+                    Err(s) => error!("[3] {}", s),
+                }
+            }
+            Err(e) => error!("[3] Framebuffer setup failed: {:?}", e),
+        }
+
+        match board::init(&mut v_mbox) {
+            Ok(info) => info.print(),
+            Err(e) => error!("[3] Could not query board information: {:?}", e),
+        }
+
+        let watchdog = hw::Watchdog::new(memory::map::physical::POWER_BASE);
+        println!("[i] Last reset: {}", watchdog.reset_reason().name());
+
+        clocks::print(&mut v_mbox);
+
+        // The MiniUart assumed the default core clock.
+        clocks::refresh(&mut v_mbox);
+
         //------------------------------------------------------------
-        // Instantiate PL011 UART and replace MiniUart with it in CONSOLE
+        // Bring up PL011 UART and replace MiniUart with it in CONSOLE
         //------------------------------------------------------------
-        let pl011_uart = hw::PL011Uart::new(memory::map::physical::PL011_UART_BASE);
 
         // uart.init() will reconfigure the GPIO, which causes a race against
         // the MiniUart that is still putting out characters on the physical
@@ -132,24 +284,32 @@ fn kernel_entry() -> ! {
         //
         // If you switch to an output that happens to not use the same pair of
         // physical wires (e.g. the Framebuffer), you don't need to do this,
-        // because flush() is anyways called implicitly by replace_with(). This
+        // because flush() is anyways called implicitly by remove_output(). This
         // is just a special case.
         CONSOLE.lock(|c| c.flush());
-        match pl011_uart.init(&mut v_mbox, &gpio) {
+        match PL011_UART.init(&mut v_mbox, &gpio, &serial_config) {
             Ok(_) => {
                 CONSOLE.lock(|c| {
-                    c.replace_with(pl011_uart.into());
+                    // Both UARTs are wired to GPIO 14 and 15, so there is
+                    // no point in mirroring to the MiniUart anymore.
+                    if let Some(id) = mini_uart_output.take() {
+                        c.remove_output(id);
+                    }
+
+                    if c.add_output(&PL011_UART).is_ok() {
+                        c.set_input(Some(&PL011_UART));
+                    }
                 });
 
                 println!("[4] PL011 UART online. Output switched to it.");
             }
 
-            Err(_) => println!(
-                "[4][Error] PL011 UART init failed. \
-                 Trying to continue with MiniUart."
-            ),
+            Err(_) => error!("[4] PL011 UART init failed. Trying to continue with MiniUart."),
         }
 
+        // From here on, the mailbox is shared.
+        MBOX.lock(|m| *m = Some(v_mbox));
+
         //------------------------------------------------------------
         // Set up exception vectors and cause an exception
         //------------------------------------------------------------
@@ -160,7 +320,7 @@ fn kernel_entry() -> ! {
         } {
             println!("[5] Exception vectors are set up.");
         } else {
-            println!("[5][Error] Error setting exception vectors. Aborting.");
+            error!("[5] Error setting exception vectors. Aborting.");
             break 'init;
         }
 
@@ -173,14 +333,127 @@ fn kernel_entry() -> ! {
         unsafe { core::ptr::read_volatile(big_addr as *mut u64) };
 
         println!("[i] Whoa! We recovered from an exception.");
+
+        //------------------------------------------------------------
+        // Demand paging and copy-on-write in a user address space
+        //------------------------------------------------------------
+        let mut parent = match memory::mmu::AddressSpace::new() {
+            Ok(i) => i,
+            Err(s) => {
+                error!("[6] {} Aborting.", s);
+                break 'init;
+            }
+        };
+
+        let user_addr = memory::map::virt::USER_START;
+        if let Err(s) = parent.map_anonymous(user_addr, 4096, Default::default()) {
+            error!("[6] {} Aborting.", s);
+            break 'init;
+        }
+
+        // The first access faults and gets a zeroed frame.
+        parent.activate();
+        unsafe { core::ptr::write_volatile(user_addr as *mut u64, 1) };
+
+        let mut child = match parent.fork() {
+            Ok(i) => i,
+            Err(s) => {
+                error!("[6] {} Aborting.", s);
+                break 'init;
+            }
+        };
+
+        // Writing to the now shared page gives the child its own copy.
+        child.activate();
+        unsafe { core::ptr::write_volatile(user_addr as *mut u64, 2) };
+
+        parent.activate();
+        let parent_val = unsafe { core::ptr::read_volatile(user_addr as *const u64) };
+        memory::mmu::activate_kernel_tables();
+
+        if parent_val == 1 {
+            println!("[6] Demand paging and copy-on-write work.");
+        } else {
+            error!("[6] Copy-on-write is broken.");
+        }
+
+        //------------------------------------------------------------
+        // Enable interrupts
+        //------------------------------------------------------------
+        if let Err(s) = timer::init().and_then(|_| DMA.lock(|d| d.init())) {
+            error!("[7] {} Aborting.", s);
+            break 'init;
+        }
+        interrupt::local_irq_enable();
+        println!("[7] Interrupts enabled.");
+
+        //------------------------------------------------------------
+        // Let the DMA engine copy and fill a buffer
+        //------------------------------------------------------------
+        // The engine's destination must not share cache lines with other
+        // data, which 64 byte alignment ensures on the Cortex-A53.
+        #[repr(C, align(64))]
+        struct CacheAligned([u8; 256]);
+
+        let src: [u8; 256] = {
+            let mut a = [0; 256];
+            for (i, x) in a.iter_mut().enumerate() {
+                *x = i as u8;
+            }
+            a
+        };
+        let mut dest = CacheAligned([0; 256]);
+        let dest = &mut dest.0;
+
+        if let Err(e) = hw::dma_memcpy(dest, &src) {
+            error!("[8] DMA memcpy failed: {:?}", e);
+        } else if dest[..] != src[..] {
+            error!("[8] DMA memcpy corrupted the data.");
+        } else if let Err(e) = hw::dma_memset(dest, 0xA5) {
+            error!("[8] DMA memset failed: {:?}", e);
+        } else if dest.iter().any(|x| *x != 0xA5) {
+            error!("[8] DMA memset corrupted the data.");
+        } else {
+            println!("[8] DMA engine copies and fills memory.");
+        }
+
+        match dma_2d_demo() {
+            Ok(true) => println!("[8] DMA engine copies rectangles in 2D mode."),
+            Ok(false) => error!("[8] DMA 2D copy corrupted the data or did not complete."),
+            Err(e) => error!("[8] DMA 2D copy failed: {:?}", e),
+        }
+
+        //------------------------------------------------------------
+        // Start thermal monitoring and frequency scaling
+        //------------------------------------------------------------
+        let ret = MBOX.lock(|m| match m.as_mut() {
+            Some(v_mbox) => thermal::init(v_mbox)
+                .and_then(|_| thermal::print_status(v_mbox).map_err(|_| "Mailbox query failed.")),
+            None => Err("No mailbox."),
+        });
+
+        match ret {
+            Ok(_) => println!("[9] Thermal monitoring online."),
+            Err(s) => error!("[9] {}", s),
+        }
+
+        //------------------------------------------------------------
+        // Arm the watchdog
+        //------------------------------------------------------------
+        match watchdog.start_with_keep_alive(WATCHDOG_TIMEOUT_MS) {
+            Ok(_) => println!(
+                "[10] Watchdog armed with a {} ms timeout.",
+                WATCHDOG_TIMEOUT_MS
+            ),
+            Err(s) => error!("[10] {}", s),
+        }
     }
 
     //------------------------------------------------------------
     // Start a command prompt
     //------------------------------------------------------------
-    CONSOLE.lock(|c| {
-        c.command_prompt();
-    })
+    println!("\nType 'help' for a list of commands.");
+    devices::virt::command_prompt(&CONSOLE, shell::dispatch)
 }
 
 raspi3_boot::entry!(kernel_entry);
@@ -26,9 +26,15 @@ use crate::println;
 use core::fmt;
 use core::ops::RangeInclusive;
 
-mod bump_allocator;
-pub use bump_allocator::BumpAllocator;
+mod frame_allocator;
+mod free_list_allocator;
 
+pub use frame_allocator::FrameAllocator;
+pub use free_list_allocator::FreeListAllocator;
+
+pub mod bus;
+pub mod cache;
+pub mod dma;
 pub mod mmu;
 
 /// System memory map.
@@ -39,7 +45,12 @@ pub mod map {
 
     pub mod physical {
         pub const MMIO_BASE:           usize =             0x3F00_0000;
+        pub const SYS_TIMER_BASE:      usize = MMIO_BASE + 0x0000_3000;
+        pub const DMA_BASE:            usize = MMIO_BASE + 0x0000_7000;
+        pub const IRQ_CTRL_BASE:       usize = MMIO_BASE + 0x0000_B200;
         pub const VIDEOCORE_MBOX_BASE: usize = MMIO_BASE + 0x0000_B880;
+        pub const POWER_BASE:          usize = MMIO_BASE + 0x0010_001C;
+        pub const RNG_BASE:            usize = MMIO_BASE + 0x0010_4000;
         pub const GPIO_BASE:           usize = MMIO_BASE + 0x0020_0000;
         pub const PL011_UART_BASE:     usize = MMIO_BASE + 0x0020_1000;
         pub const MINI_UART_BASE:      usize = MMIO_BASE + 0x0021_5000;
@@ -50,9 +61,107 @@ pub mod map {
         pub const KERN_STACK_START:    usize =             super::START;
         pub const KERN_STACK_END:      usize =             0x0007_FFFF;
 
+        // The first half of the stack area holds the per-core stacks, the
+        // second half the per-core exception stacks. See kernel_stack.
+        pub const KERN_EXC_STACK_START: usize =            0x0004_0000;
+
         // The second 2 MiB block.
         pub const DMA_HEAP_START:      usize =             0x0020_0000;
-        pub const DMA_HEAP_END:        usize =             0x005F_FFFF;
+        pub const DMA_HEAP_END:        usize =             0x003F_FFFF;
+
+        // The third 2 MiB block, for DMA buffers with cache maintenance.
+        pub const CACHED_DMA_HEAP_START: usize =           0x0040_0000;
+        pub const CACHED_DMA_HEAP_END:   usize =           0x005F_FFFF;
+
+        // Frames for page tables and user memory.
+        pub const PAGE_POOL_START:     usize =             0x0060_0000;
+        pub const PAGE_POOL_END:       usize =             0x009F_FFFF;
+
+        // The second GiB of address space is private to each address space.
+        pub const USER_START:          usize =             0x4000_0000;
+        pub const USER_END:            usize =             0x7FFF_FFFF;
+    }
+}
+
+/// The kernel stacks.
+///
+/// The stack area is cut into slots of 64 KiB, one per core for the normal
+/// stack and one per core for the exception stack. The lowest 4 KiB page of
+/// each slot is a guard page that is left unmapped, so that a stack growing
+/// past its end causes a translation fault instead of silently corrupting the
+/// memory below.
+///
+///      0x0000_0000 - 0x0003_FFFF | Stacks of core 0..3 (core 0 is the boot stack)
+///      0x0004_0000 - 0x0007_FFFF | Exception stacks of core 0..3
+pub mod kernel_stack {
+    use super::map;
+    use core::ops::RangeInclusive;
+
+    pub const NUM_CORES: usize = 4;
+    pub const SLOT_SIZE: usize = 64 * 1024;
+    pub const GUARD_SIZE: usize = 4 * 1024;
+
+    #[derive(Copy, Clone)]
+    pub enum Kind {
+        Normal,
+        Exception,
+    }
+
+    fn slot_start(kind: Kind, core: usize) -> usize {
+        let area_start = match kind {
+            Kind::Normal => map::virt::KERN_STACK_START,
+            Kind::Exception => map::virt::KERN_EXC_STACK_START,
+        };
+
+        area_start + core * SLOT_SIZE
+    }
+
+    /// The usable part of a stack, aka the slot without its guard page.
+    pub fn range(kind: Kind, core: usize) -> RangeInclusive<usize> {
+        let start = slot_start(kind, core);
+
+        RangeInclusive::new(start + GUARD_SIZE, start + SLOT_SIZE - 1)
+    }
+
+    /// The initial stack pointer of a stack, aka the address of the first byte
+    /// after it.
+    pub fn top(kind: Kind, core: usize) -> usize {
+        range(kind, core).end() + 1
+    }
+
+    /// If `addr` is inside a guard page, return the kind of stack and the core
+    /// it belongs to.
+    pub fn guard_page_owner(addr: usize) -> Option<(Kind, usize)> {
+        if !(map::virt::KERN_STACK_START..=map::virt::KERN_STACK_END).contains(&addr) {
+            return None;
+        }
+
+        if (addr - map::virt::KERN_STACK_START) % SLOT_SIZE >= GUARD_SIZE {
+            return None;
+        }
+
+        if addr >= map::virt::KERN_EXC_STACK_START {
+            let core = (addr - map::virt::KERN_EXC_STACK_START) / SLOT_SIZE;
+            Some((Kind::Exception, core))
+        } else {
+            let core = (addr - map::virt::KERN_STACK_START) / SLOT_SIZE;
+            Some((Kind::Normal, core))
+        }
+    }
+
+    /// If a fault at `addr` with the stack pointer at `sp` is a stack
+    /// overflow, return the kind of stack and the core it belongs to.
+    ///
+    /// A fault in a guard page alone is not enough. Core 0's guard page starts
+    /// at address 0, so NULL pointer dereferences land there as well. An
+    /// overflowing stack faults right next to its SP.
+    pub fn overflow_owner(addr: usize, sp: usize) -> Option<(Kind, usize)> {
+        let distance = if sp > addr { sp - addr } else { addr - sp };
+        if distance > GUARD_SIZE {
+            return None;
+        }
+
+        guard_page_owner(addr)
     }
 }
 
@@ -113,11 +222,26 @@ use kernel_mem_range::*;
 ///
 /// Contains only special ranges, aka anything that is _not_ normal cacheable
 /// DRAM.
-static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
+static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 8] = [
     Descriptor {
-        name: "Kernel stack",
+        name: "Kernel stacks (4 KiB guard page per 64 KiB)",
         virtual_range: || {
-            RangeInclusive::new(map::virt::KERN_STACK_START, map::virt::KERN_STACK_END)
+            RangeInclusive::new(
+                map::virt::KERN_STACK_START,
+                map::virt::KERN_EXC_STACK_START - 1,
+            )
+        },
+        translation: Translation::Identity,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Kernel exception stacks (4 KiB guard page per 64 KiB)",
+        virtual_range: || {
+            RangeInclusive::new(map::virt::KERN_EXC_STACK_START, map::virt::KERN_STACK_END)
         },
         translation: Translation::Identity,
         attribute_fields: AttributeFields {
@@ -191,6 +315,31 @@ static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
             execute_never: true,
         },
     },
+    Descriptor {
+        name: "Cached DMA heap pool",
+        virtual_range: || {
+            RangeInclusive::new(
+                map::virt::CACHED_DMA_HEAP_START,
+                map::virt::CACHED_DMA_HEAP_END,
+            )
+        },
+        translation: Translation::Identity,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Page frame pool",
+        virtual_range: || RangeInclusive::new(map::virt::PAGE_POOL_START, map::virt::PAGE_POOL_END),
+        translation: Translation::Identity,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
     Descriptor {
         name: "Device MMIO",
         virtual_range: || RangeInclusive::new(map::physical::MMIO_BASE, map::physical::MMIO_END),
@@ -207,12 +356,18 @@ static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
 /// according attributes.
 ///
 /// If the address is not covered in VIRTUAL_LAYOUT, return a default for normal
-/// cacheable DRAM.
-fn get_virt_addr_properties(virt_addr: usize) -> Result<(usize, AttributeFields), &'static str> {
+/// cacheable DRAM. Returns `Ok(None)` for addresses that must stay unmapped.
+fn get_virt_addr_properties(
+    virt_addr: usize,
+) -> Result<Option<(usize, AttributeFields)>, &'static str> {
     if virt_addr > map::END {
         return Err("Address out of range.");
     }
 
+    if kernel_stack::guard_page_owner(virt_addr).is_some() {
+        return Ok(None);
+    }
+
     for i in KERNEL_VIRTUAL_LAYOUT.iter() {
         if (i.virtual_range)().contains(&virt_addr) {
             let output_addr = match i.translation {
@@ -220,11 +375,55 @@ fn get_virt_addr_properties(virt_addr: usize) -> Result<(usize, AttributeFields)
                 Translation::Offset(a) => a + (virt_addr - (i.virtual_range)().start()),
             };
 
-            return Ok((output_addr, i.attribute_fields));
+            return Ok(Some((output_addr, i.attribute_fields)));
+        }
+    }
+
+    Ok(Some((virt_addr, AttributeFields::default())))
+}
+
+/// Check that the kernel can access `virt_addr` without faulting, for
+/// writing if `write` is set.
+pub fn check_kernel_access(virt_addr: usize, write: bool) -> Result<(), &'static str> {
+    match get_virt_addr_properties(virt_addr)? {
+        None => Err("Address is not mapped."),
+        Some((_, attr)) => match attr.acc_perms {
+            AccessPermissions::ReadOnly if write => Err("Address is read-only."),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Give the 2 MiB blocks covering `range` new attributes, e.g. to map a
+/// buffer that the VideoCore allocated as non-cacheable.
+///
+/// The blocks must not overlap any range of the kernel's layout.
+pub fn remap_kernel_blocks(
+    range: RangeInclusive<usize>,
+    attribute_fields: AttributeFields,
+) -> Result<(), &'static str> {
+    const TWO_MIB: usize = 2 * 1024 * 1024;
+
+    let start = *range.start() & !(TWO_MIB - 1);
+    let end = *range.end() | (TWO_MIB - 1);
+
+    if range.start() > range.end() || end > map::END {
+        return Err("Address out of range.");
+    }
+
+    for i in KERNEL_VIRTUAL_LAYOUT.iter() {
+        let r = (i.virtual_range)();
+
+        if *r.start() <= end && start <= *r.end() {
+            return Err("Range overlaps the kernel memory layout.");
         }
     }
 
-    Ok((virt_addr, AttributeFields::default()))
+    // Lines that were fetched through the cacheable mapping must not be
+    // written back later.
+    cache::clean_invalidate_range(start, end - start + 1);
+
+    unsafe { mmu::remap_blocks(start, end, attribute_fields) }
 }
 
 /// Human-readable output of a Descriptor.
@@ -0,0 +1,117 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Translation of kernel virtual addresses to VideoCore bus addresses.
+//!
+//! The GPU and the DMA engine do not go through the ARM MMU. They see SDRAM
+//! through one of four 1 GiB aliases, which differ in how the access is
+//! cached in the VideoCore L2 cache, and the peripherals at 0x7E00_0000
+//! instead of the ARM physical 0x3F00_0000.
+
+use super::map;
+
+/// The bus alias through which a device accesses SDRAM.
+#[derive(Copy, Clone)]
+pub enum Alias {
+    /// Allocating in the VideoCore L2 cache. Only coherent with ARM accesses
+    /// if the L2 is used by the ARM as well.
+    #[allow(dead_code)]
+    L2Cached,
+
+    /// Bypassing the VideoCore L2 cache. Use this for memory that is shared
+    /// with the ARM cores.
+    Uncached,
+}
+
+impl Alias {
+    fn base(self) -> u32 {
+        match self {
+            Alias::L2Cached => 0x4000_0000,
+            Alias::Uncached => 0xC000_0000,
+        }
+    }
+}
+
+/// Bus address of the peripherals.
+const PERIPHERAL_BASE: u32 = 0x7E00_0000;
+
+/// Bus address of an ARM physical address in the peripheral window. Other
+/// MMIO, like the ARM local peripherals, is not on the VideoCore bus.
+fn peripheral_bus_addr(phys_addr: usize) -> Option<u32> {
+    if (map::physical::MMIO_BASE..=map::physical::MMIO_END).contains(&phys_addr) {
+        Some(PERIPHERAL_BASE + (phys_addr - map::physical::MMIO_BASE) as u32)
+    } else {
+        None
+    }
+}
+
+/// The ARM physical address behind the bus address `bus_addr`, as handed out
+/// by the firmware. Undoes `to_bus()` for any alias.
+pub fn from_bus(bus_addr: u32) -> Result<usize, &'static str> {
+    let peripheral_end =
+        PERIPHERAL_BASE + (map::physical::MMIO_END - map::physical::MMIO_BASE) as u32;
+
+    if (PERIPHERAL_BASE..=peripheral_end).contains(&bus_addr) {
+        return Ok(map::physical::MMIO_BASE + (bus_addr - PERIPHERAL_BASE) as usize);
+    }
+
+    // The two top bits select the alias.
+    let phys_addr = (bus_addr & 0x3FFF_FFFF) as usize;
+
+    if phys_addr >= map::physical::MMIO_BASE {
+        return Err("Bus address is not in SDRAM.");
+    }
+
+    Ok(phys_addr)
+}
+
+/// The ARM physical address that the kernel virtual address `virt_addr` maps
+/// to.
+pub fn virt_to_phys(virt_addr: usize) -> Result<usize, &'static str> {
+    match super::get_virt_addr_properties(virt_addr)? {
+        Some((phys_addr, _)) => Ok(phys_addr),
+        None => Err("Address is not mapped."),
+    }
+}
+
+/// The bus address under which a device sees the kernel virtual address
+/// `virt_addr`.
+///
+/// SDRAM is reached through `alias`, peripherals through their bus window.
+pub fn to_bus(virt_addr: usize, alias: Alias) -> Result<u32, &'static str> {
+    let phys_addr = virt_to_phys(virt_addr)?;
+
+    if phys_addr < map::physical::MMIO_BASE {
+        return Ok(alias.base() | phys_addr as u32);
+    }
+
+    peripheral_bus_addr(phys_addr).ok_or("Address is not visible on the VideoCore bus.")
+}
+
+/// The bus address of the peripheral register at the kernel virtual address
+/// `virt_addr`, for example as the source or destination of a DMA transfer.
+#[allow(dead_code)]
+pub fn peripheral(virt_addr: usize) -> Result<u32, &'static str> {
+    peripheral_bus_addr(virt_to_phys(virt_addr)?).ok_or("Address is not a peripheral.")
+}
@@ -0,0 +1,195 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Data cache maintenance.
+//!
+//! Cacheable memory that is shared with a DMA master, which does not snoop the
+//! CPU caches, needs explicit maintenance to the point of coherency:
+//!
+//! - Before the device reads a buffer, dirty lines must be cleaned (written
+//!   back) so that the device sees the CPU's data.
+//! - Before the CPU reads what the device wrote, the buffer's lines must be
+//!   invalidated so that no stale data is read from the cache.
+
+use cortex_a::barrier;
+
+/// Cache Type Register.
+fn ctr_el0() -> u64 {
+    let ctr;
+    unsafe { asm!("mrs $0, CTR_EL0" : "=r"(ctr) ::: "volatile") };
+    ctr
+}
+
+/// Size of the smallest data cache line in the system, in bytes.
+pub fn dcache_line_size() -> usize {
+    // CTR_EL0.DminLine: log2 of the number of words in the smallest line.
+    let dminline = (ctr_el0() >> 16) & 0xF;
+
+    4 << dminline
+}
+
+#[derive(Copy, Clone)]
+enum Op {
+    Clean,
+    Invalidate,
+    CleanInvalidate,
+}
+
+/// Apply `op` by virtual address to all lines covering `[start, start + size)`.
+fn range_op(op: Op, start: usize, size: usize) {
+    if size == 0 {
+        return;
+    }
+
+    let line_size = dcache_line_size();
+    let end = start + size;
+    let mut addr = start & !(line_size - 1);
+
+    while addr < end {
+        // Invalidating a partial line would throw away the CPU's data in the
+        // part that is not ours, so clean those lines as well.
+        let partial = addr < start || addr + line_size > end;
+
+        unsafe {
+            match op {
+                Op::Clean => asm!("dc cvac, $0" :: "r"(addr) :: "volatile"),
+                Op::Invalidate if !partial => asm!("dc ivac, $0" :: "r"(addr) :: "volatile"),
+                _ => asm!("dc civac, $0" :: "r"(addr) :: "volatile"),
+            }
+        }
+
+        addr += line_size;
+    }
+
+    unsafe { barrier::dsb(barrier::SY) };
+}
+
+/// Write back dirty lines of a VA range to the point of coherency.
+pub fn clean_range(start: usize, size: usize) {
+    range_op(Op::Clean, start, size);
+}
+
+/// Discard the cached lines of a VA range. Lines that are only partially
+/// covered by the range are cleaned first.
+///
+/// # Safety
+///
+/// - Writes by the CPU to the range that were not cleaned before are lost.
+pub unsafe fn invalidate_range(start: usize, size: usize) {
+    range_op(Op::Invalidate, start, size);
+}
+
+/// Write back and discard the cached lines of a VA range.
+pub fn clean_invalidate_range(start: usize, size: usize) {
+    range_op(Op::CleanInvalidate, start, size);
+}
+
+/// Apply `op` by set/way to every line of all data and unified caches up to
+/// the level of coherency.
+///
+/// Set/way operations only affect the caches of the executing core and are not
+/// safe against concurrent accesses. They are meant for bring-up and shutdown,
+/// e.g. before turning off the MMU or the caches. Use the range operations for
+/// DMA buffers.
+fn set_way_op(op: Op) {
+    let clidr: u64;
+    unsafe { asm!("mrs $0, CLIDR_EL1" : "=r"(clidr) ::: "volatile") };
+
+    // CLIDR_EL1.LoC: Level of coherency.
+    let loc = (clidr >> 24) & 0x7;
+
+    for level in 0..loc {
+        // CLIDR_EL1.Ctype<n>: 2 and above means there is a data cache.
+        let ctype = (clidr >> (level * 3)) & 0x7;
+        if ctype < 2 {
+            continue;
+        }
+
+        let ccsidr: u64;
+        unsafe {
+            asm!("msr CSSELR_EL1, $0" :: "r"(level << 1) :: "volatile");
+            barrier::isb(barrier::SY);
+            asm!("mrs $0, CCSIDR_EL1" : "=r"(ccsidr) ::: "volatile");
+        }
+
+        let line_shift = (ccsidr & 0x7) + 4;
+        let num_ways = ((ccsidr >> 3) & 0x3FF) + 1;
+        let num_sets = ((ccsidr >> 13) & 0x7FFF) + 1;
+
+        // The way number sits in the topmost bits of the 32 bit operand.
+        let way_shift = (num_ways as u32 - 1).leading_zeros() as u64;
+
+        for way in 0..num_ways {
+            for set in 0..num_sets {
+                let operand = (way << way_shift) | (set << line_shift) | (level << 1);
+
+                unsafe {
+                    match op {
+                        Op::Clean => asm!("dc csw, $0" :: "r"(operand) :: "volatile"),
+                        Op::Invalidate => asm!("dc isw, $0" :: "r"(operand) :: "volatile"),
+                        Op::CleanInvalidate => {
+                            asm!("dc cisw, $0" :: "r"(operand) :: "volatile")
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    unsafe {
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+}
+
+/// Discard the content of the data caches, including dirty lines.
+///
+/// # Safety
+///
+/// - Writes that are only in the caches are lost.
+pub unsafe fn invalidate_all() {
+    set_way_op(Op::Invalidate);
+}
+
+/// Explicit ownership transfer of a buffer in cacheable memory between the CPU
+/// and a DMA master.
+///
+/// ```ignore
+/// buffer.sync_for_device();
+/// // Let the device read and/or write the buffer.
+/// unsafe { buffer.sync_for_cpu() };
+/// ```
+pub trait DmaSync {
+    /// Hand the buffer to the device. Makes the CPU's writes visible to it.
+    fn sync_for_device(&self);
+
+    /// Take the buffer back from the device. Makes the device's writes visible
+    /// to the CPU.
+    ///
+    /// # Safety
+    ///
+    /// - The CPU must not have written to the buffer since
+    ///   `sync_for_device()`. Those writes are discarded.
+    unsafe fn sync_for_cpu(&self);
+}
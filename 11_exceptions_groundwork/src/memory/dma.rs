@@ -0,0 +1,224 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Owned buffers in the DMA heaps.
+//!
+//! Memory from the DMA allocators is returned to them when the owning `DmaBox`
+//! or `DmaSlice` is dropped.
+
+use super::{bus, cache};
+use core::alloc::{Alloc, Layout};
+use core::marker::PhantomData;
+use core::ops;
+use core::ptr::{self, NonNull};
+use core::{mem, slice};
+
+/// The DMA heap a buffer lives in.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Heap {
+    /// Non-cacheable. Slow for the CPU, but needs no cache maintenance.
+    Uncached,
+
+    /// Cacheable. Fast for the CPU, but the buffer must be synced with
+    /// `DmaSync` around every transfer.
+    Cached,
+}
+
+/// The address under which a device on the VideoCore bus sees `addr`.
+///
+/// Devices must bypass the VideoCore L2, which does not know about the ARM's
+/// writes. Cached buffers are written back to memory by `sync_for_device()`.
+fn bus_addr_of<T>(addr: *const T) -> u32 {
+    bus::to_bus(addr as usize, bus::Alias::Uncached).expect("DMA heap is always mapped")
+}
+
+fn alloc(heap: Heap, layout: Layout) -> Result<NonNull<u8>, ()> {
+    let allocator = match heap {
+        Heap::Uncached => &crate::DMA_ALLOCATOR,
+        Heap::Cached => &crate::CACHED_DMA_ALLOCATOR,
+    };
+
+    allocator.lock(|d| unsafe { d.alloc_zeroed(layout) }.map_err(|_| ()))
+}
+
+fn dealloc(heap: Heap, ptr: NonNull<u8>, layout: Layout) {
+    let allocator = match heap {
+        Heap::Uncached => &crate::DMA_ALLOCATOR,
+        Heap::Cached => &crate::CACHED_DMA_ALLOCATOR,
+    };
+
+    allocator.lock(|d| unsafe { d.dealloc(ptr, layout) });
+}
+
+/// A single value in the uncached DMA heap.
+pub struct DmaBox<T> {
+    ptr: NonNull<T>,
+    alignment: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T> DmaBox<T> {
+    /// Move `value` into the DMA heap.
+    pub fn new(value: T) -> Result<DmaBox<T>, ()> {
+        Self::with_alignment(value, mem::align_of::<T>())
+    }
+
+    /// Move `value` into the DMA heap at an address aligned to `alignment`, or
+    /// to the alignment of `T` if that is larger.
+    pub fn with_alignment(value: T, alignment: usize) -> Result<DmaBox<T>, ()> {
+        let alignment = alignment.max(mem::align_of::<T>());
+        let layout = Layout::from_size_align(mem::size_of::<T>(), alignment).map_err(|_| ())?;
+        let ptr = alloc(Heap::Uncached, layout)?.cast::<T>();
+
+        unsafe { ptr::write(ptr.as_ptr(), value) };
+
+        Ok(DmaBox {
+            ptr,
+            alignment,
+            _marker: PhantomData,
+        })
+    }
+
+    /// The address of the value as seen by DMA masters.
+    pub fn bus_addr(&self) -> u32 {
+        bus_addr_of(self.ptr.as_ptr())
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(mem::size_of::<T>(), self.alignment).unwrap()
+    }
+}
+
+impl<T> ops::Deref for DmaBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> ops::DerefMut for DmaBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for DmaBox<T> {
+    fn drop(&mut self) {
+        let layout = self.layout();
+
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+        dealloc(Heap::Uncached, self.ptr.cast(), layout);
+    }
+}
+
+/// Plain data types, for which all-zero bytes are a valid value.
+///
+/// # Safety
+///
+/// - Implementors must not contain references, `NonZero*` types, enums or
+///   anything else that has invalid bit patterns.
+pub unsafe trait Zeroable: Copy {}
+
+unsafe impl Zeroable for u8 {}
+unsafe impl Zeroable for u16 {}
+unsafe impl Zeroable for u32 {}
+unsafe impl Zeroable for u64 {}
+unsafe impl Zeroable for usize {}
+
+/// A zero-initialized slice in one of the DMA heaps.
+pub struct DmaSlice<T: Zeroable> {
+    ptr: NonNull<T>,
+    len: usize,
+    alignment: usize,
+    heap: Heap,
+}
+
+impl<T: Zeroable> DmaSlice<T> {
+    /// Allocate `len` zeroed items in the uncached heap, at an address aligned
+    /// to `alignment`, or to the alignment of `T` if that is larger.
+    pub fn new_zeroed(len: usize, alignment: usize) -> Result<DmaSlice<T>, ()> {
+        Self::new_zeroed_in(Heap::Uncached, len, alignment)
+    }
+
+    /// Same as `new_zeroed()`, but in `heap`.
+    pub fn new_zeroed_in(heap: Heap, len: usize, alignment: usize) -> Result<DmaSlice<T>, ()> {
+        let alignment = alignment.max(mem::align_of::<T>());
+        let size = len.checked_mul(mem::size_of::<T>()).ok_or(())?;
+        let layout = Layout::from_size_align(size, alignment).map_err(|_| ())?;
+        let ptr = alloc(heap, layout)?.cast::<T>();
+
+        Ok(DmaSlice {
+            ptr,
+            len,
+            alignment,
+            heap,
+        })
+    }
+
+    /// The address of the first item as seen by DMA masters.
+    pub fn bus_addr(&self) -> u32 {
+        bus_addr_of(self.ptr.as_ptr())
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.len * mem::size_of::<T>(), self.alignment).unwrap()
+    }
+}
+
+impl<T: Zeroable> ops::Deref for DmaSlice<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Zeroable> ops::DerefMut for DmaSlice<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+/// Only slices in the cached heap need maintenance, for the others these are
+/// no-ops.
+impl<T: Zeroable> cache::DmaSync for DmaSlice<T> {
+    fn sync_for_device(&self) {
+        if self.heap == Heap::Cached {
+            cache::clean_range(self.ptr.as_ptr() as usize, self.len * mem::size_of::<T>());
+        }
+    }
+
+    unsafe fn sync_for_cpu(&self) {
+        if self.heap == Heap::Cached {
+            cache::invalidate_range(self.ptr.as_ptr() as usize, self.len * mem::size_of::<T>());
+        }
+    }
+}
+
+impl<T: Zeroable> Drop for DmaSlice<T> {
+    fn drop(&mut self) {
+        dealloc(self.heap, self.ptr.cast(), self.layout());
+    }
+}
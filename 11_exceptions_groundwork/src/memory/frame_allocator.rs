@@ -0,0 +1,109 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::memory::map;
+use core::ptr;
+
+pub const FRAME_SIZE: usize = 4 * 1024;
+
+const NUM_FRAMES: usize = (map::virt::PAGE_POOL_END - map::virt::PAGE_POOL_START + 1) / FRAME_SIZE;
+
+/// Hands out 4 KiB frames from the page pool.
+///
+/// Frames are reference counted, so that they can be shared between address
+/// spaces, e.g. for copy-on-write. A frame returns to the pool when its last
+/// reference is freed.
+///
+/// The pool is identity mapped, so the returned addresses are valid both as
+/// physical and as kernel virtual addresses.
+pub struct FrameAllocator {
+    pool_start: usize,
+    refcount: [u8; NUM_FRAMES],
+}
+
+impl FrameAllocator {
+    pub const fn new(pool_start: usize) -> Self {
+        Self {
+            pool_start,
+            refcount: [0; NUM_FRAMES],
+        }
+    }
+
+    fn frame_nr(&self, frame: usize) -> Option<usize> {
+        if frame < self.pool_start {
+            return None;
+        }
+
+        let frame_nr = (frame - self.pool_start) / FRAME_SIZE;
+        if frame_nr >= NUM_FRAMES {
+            return None;
+        }
+
+        Some(frame_nr)
+    }
+
+    /// Allocate a frame. Its content is undefined.
+    pub fn alloc(&mut self) -> Result<usize, ()> {
+        match self.refcount.iter().position(|r| *r == 0) {
+            None => Err(()),
+            Some(frame_nr) => {
+                self.refcount[frame_nr] = 1;
+
+                Ok(self.pool_start + frame_nr * FRAME_SIZE)
+            }
+        }
+    }
+
+    /// Allocate a frame that is filled with zeroes.
+    pub fn alloc_zeroed(&mut self) -> Result<usize, ()> {
+        let frame = self.alloc()?;
+        unsafe { ptr::write_bytes(frame as *mut u8, 0, FRAME_SIZE) };
+
+        Ok(frame)
+    }
+
+    /// Take an additional reference to an allocated frame.
+    pub fn share(&mut self, frame: usize) -> Result<(), ()> {
+        match self.frame_nr(frame) {
+            Some(nr) if self.refcount[nr] > 0 && self.refcount[nr] < u8::max_value() => {
+                self.refcount[nr] += 1;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Number of references to a frame. Zero for free frames and for addresses
+    /// outside of the pool.
+    pub fn refcount(&self, frame: usize) -> u8 {
+        self.frame_nr(frame).map_or(0, |nr| self.refcount[nr])
+    }
+
+    /// Drop a reference to a frame. Addresses outside of the pool are ignored.
+    pub fn free(&mut self, frame: usize) {
+        if let Some(nr) = self.frame_nr(frame) {
+            self.refcount[nr] = self.refcount[nr].saturating_sub(1);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::trace;
+use core::alloc::{Alloc, AllocErr, Layout};
+use core::ptr::{self, NonNull};
+
+/// A free block of the pool. The header lives in the block itself.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Granularity of all allocations. Every block can hold a FreeBlock header.
+const MIN_BLOCK_SIZE: usize = 16;
+
+/// A first-fit allocator with a free list that is sorted by address.
+///
+/// Freed blocks are merged with their free neighbours, so that the pool does
+/// not fragment into ever smaller pieces.
+pub struct FreeListAllocator {
+    pool_start: usize,
+    pool_end: usize,
+    head: *mut FreeBlock,
+    initialized: bool,
+    name: &'static str,
+}
+
+// The pool is only accessed through the global lock.
+unsafe impl Send for FreeListAllocator {}
+
+fn round_up(size: usize) -> usize {
+    crate::memory::aligned_addr_unchecked(size, MIN_BLOCK_SIZE)
+}
+
+/// Size and alignment as used internally for `layout`.
+fn block_layout(layout: Layout) -> (usize, usize) {
+    (
+        round_up(layout.size().max(1)),
+        layout.align().max(MIN_BLOCK_SIZE),
+    )
+}
+
+unsafe impl Alloc for FreeListAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        self.init();
+
+        let (size, align) = block_layout(layout);
+
+        // Pointer to the `next` field that points to the current block.
+        let mut link: *mut *mut FreeBlock = &mut self.head;
+        while !(*link).is_null() {
+            let block = *link;
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+
+            let start = crate::memory::aligned_addr_unchecked(block_start, align);
+            let end = start + size;
+
+            if end <= block_end {
+                let next = (*block).next;
+
+                // Give back the tail of the block.
+                let after = if end < block_end {
+                    let tail = end as *mut FreeBlock;
+                    ptr::write(
+                        tail,
+                        FreeBlock {
+                            size: block_end - end,
+                            next,
+                        },
+                    );
+                    tail
+                } else {
+                    next
+                };
+
+                // Give back the head of the block that was skipped for
+                // alignment. Both are multiples of MIN_BLOCK_SIZE.
+                if start > block_start {
+                    (*block).size = start - block_start;
+                    (*block).next = after;
+                } else {
+                    *link = after;
+                }
+
+                trace!(
+                    "{}: Allocated Addr {:#010X} Size {:#X}",
+                    self.name,
+                    start,
+                    layout.size()
+                );
+
+                return Ok(NonNull::new_unchecked(start as *mut u8));
+            }
+
+            link = &mut (*block).next;
+        }
+
+        Err(AllocErr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        let start = ptr.as_ptr() as usize;
+
+        // Find the neighbours in the sorted list.
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        ptr::write(block, FreeBlock { size, next });
+
+        // Merge with the following block.
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        // Merge with the preceding block, or link it to the new one.
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+
+        trace!(
+            "{}: Freed Addr {:#010X} Size {:#X}",
+            self.name,
+            start,
+            layout.size()
+        );
+    }
+}
+
+impl FreeListAllocator {
+    /// Create an allocator for the inclusive range `pool_start..=pool_end`.
+    pub const fn new(pool_start: usize, pool_end: usize, name: &'static str) -> Self {
+        Self {
+            pool_start,
+            pool_end,
+            head: ptr::null_mut(),
+            initialized: false,
+            name,
+        }
+    }
+
+    /// Put the whole pool into the free list on first use. This can not be
+    /// done in the const constructor.
+    unsafe fn init(&mut self) {
+        if self.initialized {
+            return;
+        }
+
+        let start = round_up(self.pool_start);
+        let end = (self.pool_end + 1) & !(MIN_BLOCK_SIZE - 1);
+
+        self.head = start as *mut FreeBlock;
+        ptr::write(
+            self.head,
+            FreeBlock {
+                size: end - start,
+                next: ptr::null_mut(),
+            },
+        );
+
+        self.initialized = true;
+    }
+}
@@ -22,13 +22,24 @@
  * SOFTWARE.
  */
 
-use crate::memory::{get_virt_addr_properties, AttributeFields};
+use crate::memory::{cache, get_virt_addr_properties, AttributeFields};
 use cortex_a::{barrier, regs::*};
 use register::register_bitfields;
 
+mod address_space;
+mod asid;
+
+pub use address_space::{handle_fault, AddressSpace, FaultKind};
+
 register_bitfields! {u64,
     // AArch64 Reference Manual page 2150
     STAGE1_DESCRIPTOR [
+        /// Unprivileged execute-never
+        UXN      OFFSET(54) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
         /// Privileged execute-never
         PXN      OFFSET(53) NUMBITS(1) [
             False = 0,
@@ -39,6 +50,12 @@ register_bitfields! {u64,
         LVL2_OUTPUT_ADDR_4KiB    OFFSET(21) NUMBITS(27) [], // [47:21]
         NEXT_LVL_TABLE_ADDR_4KiB OFFSET(12) NUMBITS(36) [], // [47:12]
 
+        /// Not global, aka the entry is tagged with the current ASID
+        nG       OFFSET(11) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
         /// Access flag
         AF       OFFSET(10) NUMBITS(1) [
             False = 0,
@@ -103,15 +120,13 @@ impl TableDescriptor {
     }
 }
 
-/// A function that maps the generic memory range attributes to HW-specific
-/// attributes of the MMU.
-fn into_mmu_attributes(
-    attribute_fields: AttributeFields,
+/// Map the generic memory type to the HW-specific shareability and MAIR index.
+fn into_mmu_mem_attributes(
+    mem_attributes: crate::memory::MemAttributes,
 ) -> register::FieldValue<u64, STAGE1_DESCRIPTOR::Register> {
-    use crate::memory::{AccessPermissions, MemAttributes};
+    use crate::memory::MemAttributes;
 
-    // Memory attributes
-    let mut desc = match attribute_fields.mem_attributes {
+    match mem_attributes {
         MemAttributes::CacheableDRAM => {
             STAGE1_DESCRIPTOR::SH::InnerShareable + STAGE1_DESCRIPTOR::AttrIndx.val(mair::NORMAL)
         }
@@ -122,7 +137,18 @@ fn into_mmu_attributes(
         MemAttributes::Device => {
             STAGE1_DESCRIPTOR::SH::OuterShareable + STAGE1_DESCRIPTOR::AttrIndx.val(mair::DEVICE)
         }
-    };
+    }
+}
+
+/// A function that maps the generic memory range attributes to HW-specific
+/// attributes of the MMU.
+fn into_mmu_attributes(
+    attribute_fields: AttributeFields,
+) -> register::FieldValue<u64, STAGE1_DESCRIPTOR::Register> {
+    use crate::memory::AccessPermissions;
+
+    // Memory attributes
+    let mut desc = into_mmu_mem_attributes(attribute_fields.mem_attributes);
 
     // Access Permissions
     desc += match attribute_fields.acc_perms {
@@ -137,9 +163,35 @@ fn into_mmu_attributes(
         STAGE1_DESCRIPTOR::PXN::False
     };
 
+    // Kernel memory is never executable from EL0.
+    desc += STAGE1_DESCRIPTOR::UXN::True;
+
     desc
 }
 
+/// Like into_mmu_attributes(), but for memory of an user address space. It is
+/// accessible from EL0, tagged with the ASID and never executable from EL1.
+fn into_user_mmu_attributes(
+    attribute_fields: AttributeFields,
+) -> register::FieldValue<u64, STAGE1_DESCRIPTOR::Register> {
+    use crate::memory::AccessPermissions;
+
+    let mut desc = into_mmu_mem_attributes(attribute_fields.mem_attributes);
+
+    desc += match attribute_fields.acc_perms {
+        AccessPermissions::ReadOnly => STAGE1_DESCRIPTOR::AP::RO_EL1_EL0,
+        AccessPermissions::ReadWrite => STAGE1_DESCRIPTOR::AP::RW_EL1_EL0,
+    };
+
+    desc += if attribute_fields.execute_never {
+        STAGE1_DESCRIPTOR::UXN::True
+    } else {
+        STAGE1_DESCRIPTOR::UXN::False
+    };
+
+    desc + STAGE1_DESCRIPTOR::PXN::True + STAGE1_DESCRIPTOR::nG::True
+}
+
 /// A Level2 block descriptor with 2 MiB aperture.
 ///
 /// The output points to physical memory.
@@ -195,6 +247,26 @@ impl PageDescriptor {
         ))
     }
 
+    /// A page descriptor for an user address space.
+    fn new_user(
+        output_addr: usize,
+        attribute_fields: AttributeFields,
+    ) -> Result<PageDescriptor, &'static str> {
+        if output_addr % FOUR_KIB != 0 {
+            return Err("PageDescriptor: Address is not 4 KiB aligned.");
+        }
+
+        let shifted = output_addr >> FOUR_KIB_SHIFT;
+
+        Ok(PageDescriptor(
+            STAGE1_DESCRIPTOR::VALID::True
+                + STAGE1_DESCRIPTOR::AF::True
+                + into_user_mmu_attributes(attribute_fields)
+                + STAGE1_DESCRIPTOR::TYPE::Table
+                + STAGE1_DESCRIPTOR::NEXT_LVL_TABLE_ADDR_4KiB.val(shifted as u64),
+        ))
+    }
+
     fn value(&self) -> u64 {
         self.0.value
     }
@@ -251,6 +323,13 @@ struct PageTable {
     entries: [u64; NUM_ENTRIES_4KIB],
 }
 
+/// The LVL1 page table of the kernel. Its first entry forwards to the
+/// LVL2_TABLE, which covers the kernel's 1 GiB. The second GiB is left to
+/// user address spaces.
+static mut LVL1_TABLE: PageTable = PageTable {
+    entries: [0; NUM_ENTRIES_4KIB],
+};
+
 /// The LVL2 page table containng the 2 MiB entries.
 static mut LVL2_TABLE: PageTable = PageTable {
     entries: [0; NUM_ENTRIES_4KIB],
@@ -263,6 +342,61 @@ static mut LVL3_TABLE: PageTable = PageTable {
     entries: [0; NUM_ENTRIES_4KIB],
 };
 
+/// Switch TTBR0 back to the kernel's tables, which only map the kernel's GiB.
+pub fn activate_kernel_tables() {
+    unsafe {
+        TTBR0_EL1.set_baddr(LVL1_TABLE.entries.base_addr_u64());
+        barrier::isb(barrier::SY);
+    }
+
+    address_space::clear_current();
+}
+
+/// The kernel's LVL1 table entry. Every user address space shares it.
+fn kernel_lvl1_entry() -> u64 {
+    unsafe { LVL1_TABLE.entries[0] }
+}
+
+/// Give the identity mapped 2 MiB blocks covering `start..=end` new
+/// attributes.
+///
+/// Uses break-before-make, so the blocks must not be accessed meanwhile.
+pub(super) unsafe fn remap_blocks(
+    start: usize,
+    end: usize,
+    attribute_fields: AttributeFields,
+) -> Result<(), &'static str> {
+    let first = start >> TWO_MIB_SHIFT;
+    let last = end >> TWO_MIB_SHIFT;
+
+    if first == 0 || first > last || last >= NUM_ENTRIES_4KIB {
+        return Err("Blocks out of the range of the LVL2 table.");
+    }
+
+    // Fail before the break, so that an error leaves the old mapping intact.
+    for nr in first..=last {
+        Lvl2BlockDescriptor::new(nr << TWO_MIB_SHIFT, attribute_fields)?;
+    }
+
+    for entry in LVL2_TABLE.entries[first..=last].iter_mut() {
+        *entry = 0;
+    }
+
+    barrier::dsb(barrier::SY);
+    asm!("tlbi vmalle1is" :::: "volatile");
+    barrier::dsb(barrier::SY);
+
+    for nr in first..=last {
+        LVL2_TABLE.entries[nr] =
+            Lvl2BlockDescriptor::new(nr << TWO_MIB_SHIFT, attribute_fields)?.value();
+    }
+
+    barrier::dsb(barrier::SY);
+    barrier::isb(barrier::SY);
+
+    Ok(())
+}
+
 /// Set up identity mapped page tables for the first 1 GiB of address space.
 ///
 /// The first 2 MiB are 4 KiB granule, the rest 2 MiB.
@@ -270,6 +404,12 @@ pub unsafe fn init() -> Result<(), &'static str> {
     // Prepare the memory attribute indirection register.
     set_up_mair();
 
+    // The kernel's GiB.
+    LVL1_TABLE.entries[0] = match TableDescriptor::new(LVL2_TABLE.entries.base_addr_usize()) {
+        Err(s) => return Err(s),
+        Ok(d) => d.value(),
+    };
+
     // Point the first 2 MiB of virtual addresses to the follow-up LVL3
     // page-table.
     LVL2_TABLE.entries[0] = match TableDescriptor::new(LVL3_TABLE.entries.base_addr_usize()) {
@@ -286,7 +426,8 @@ pub unsafe fn init() -> Result<(), &'static str> {
 
         let (output_addr, attribute_fields) = match get_virt_addr_properties(virt_addr) {
             Err(s) => return Err(s),
-            Ok((a, b)) => (a, b),
+            Ok(Some((a, b))) => (a, b),
+            Ok(None) => return Err("Unmapped ranges must be covered by the LVL3 table."),
         };
 
         let block_desc = match Lvl2BlockDescriptor::new(output_addr, attribute_fields) {
@@ -303,7 +444,12 @@ pub unsafe fn init() -> Result<(), &'static str> {
 
         let (output_addr, attribute_fields) = match get_virt_addr_properties(virt_addr) {
             Err(s) => return Err(s),
-            Ok((a, b)) => (a, b),
+            Ok(Some((a, b))) => (a, b),
+            Ok(None) => {
+                // Leave the entry invalid, e.g. for stack guard pages.
+                *entry = 0;
+                continue;
+            }
         };
 
         let page_desc = match PageDescriptor::new(output_addr, attribute_fields) {
@@ -314,8 +460,8 @@ pub unsafe fn init() -> Result<(), &'static str> {
         *entry = page_desc.value();
     }
 
-    // Point to the LVL2 table base address in TTBR0.
-    TTBR0_EL1.set_baddr(LVL2_TABLE.entries.base_addr_u64());
+    // Point to the LVL1 table base address in TTBR0. The kernel uses ASID 0.
+    TTBR0_EL1.set_baddr(LVL1_TABLE.entries.base_addr_u64());
 
     // Configure various settings of stage 1 of the EL1 translation regime.
     let ips = ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange);
@@ -327,9 +473,16 @@ pub unsafe fn init() -> Result<(), &'static str> {
             + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
             + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
             + TCR_EL1::EPD0::EnableTTBR0Walks
-            + TCR_EL1::T0SZ.val(34), // Start walks at level 2
+            + TCR_EL1::T0SZ.val(33), // 2 GiB, start walks at level 1
     );
 
+    // Use 16 bit ASIDs if the CPU supports them.
+    asid::init();
+
+    // The data cache was off so far. Lines it may hold from before the reset
+    // must not shadow what was written to memory since.
+    cache::invalidate_all();
+
     // Switch the MMU on.
     //
     // First, force all previous changes to be seen before the MMU is enabled.
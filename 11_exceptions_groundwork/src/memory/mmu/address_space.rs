@@ -0,0 +1,507 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::{
+    asid, PageDescriptor, PageTable, TableDescriptor, FOUR_KIB, FOUR_KIB_SHIFT, NUM_ENTRIES_4KIB,
+    TWO_MIB_SHIFT,
+};
+use crate::memory::{map, AccessPermissions, AttributeFields};
+use crate::sync::NullLock;
+use core::ptr;
+use cortex_a::{barrier, regs::*};
+
+/// Descriptor bit 0, set for all valid entries.
+const VALID: u64 = 1;
+
+/// AP[2], set for read-only pages.
+const AP_READ_ONLY: u64 = 1 << 7;
+
+/// Software defined descriptor bit that marks a copy-on-write page.
+const SW_COW: u64 = 1 << 55;
+
+/// Output address bits [47:12] of table and page descriptors.
+const OUTPUT_ADDR_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+
+const MAX_VMAS: usize = 16;
+
+/// A virtual memory area, aka a range of user addresses that is backed lazily
+/// with zeroed frames on first access.
+#[derive(Copy, Clone)]
+struct Vma {
+    start: usize,
+    end: usize,
+    attribute_fields: AttributeFields,
+}
+
+impl Vma {
+    fn contains(&self, addr: usize) -> bool {
+        (self.start..=self.end).contains(&addr)
+    }
+
+    fn is_writable(&self) -> bool {
+        match self.attribute_fields.acc_perms {
+            AccessPermissions::ReadWrite => true,
+            AccessPermissions::ReadOnly => false,
+        }
+    }
+}
+
+/// The kind of a fault in user memory, as decoded from the data fault status
+/// code.
+pub enum FaultKind {
+    Translation,
+    Permission,
+}
+
+/// The state of an address space. It lives in a frame of its own, so that it
+/// stays put when the AddressSpace handle moves, and the fault handler can
+/// find it through CURRENT.
+struct Inner {
+    lvl1: usize,
+    lvl2: usize,
+    asid: asid::Asid,
+    vmas: [Option<Vma>; MAX_VMAS],
+}
+
+/// The page tables of a user address space, tagged with an ASID.
+///
+/// The first GiB is the kernel's and shared by every address space. The second
+/// GiB, `map::virt::USER_START..=map::virt::USER_END`, is private and mapped
+/// with 4 KiB pages. All tables, and all frames mapped into the address space,
+/// are owned by it and returned to the frame allocator when it is dropped.
+pub struct AddressSpace {
+    inner: usize,
+}
+
+/// The `Inner` of the active address space, or 0 if the kernel's tables are
+/// active.
+static CURRENT: NullLock<usize> = NullLock::new(0);
+
+/// Get a table from the address of its frame. The page pool is identity mapped.
+unsafe fn table(addr: usize) -> &'static mut PageTable {
+    &mut *(addr as *mut PageTable)
+}
+
+fn alloc_frame(zeroed: bool) -> Result<usize, &'static str> {
+    crate::FRAME_ALLOCATOR
+        .lock(|f| if zeroed { f.alloc_zeroed() } else { f.alloc() })
+        .map_err(|_| "AddressSpace: Out of page frames.")
+}
+
+fn free_frame(frame: usize) {
+    crate::FRAME_ALLOCATOR.lock(|f| f.free(frame));
+}
+
+fn is_user_addr(virt_addr: usize) -> bool {
+    (map::virt::USER_START..=map::virt::USER_END).contains(&virt_addr)
+}
+
+/// Indices into the LVL2 and LVL3 tables of the user GiB.
+fn indices(virt_addr: usize) -> (usize, usize) {
+    let offset = virt_addr - map::virt::USER_START;
+
+    (
+        offset >> TWO_MIB_SHIFT,
+        (offset >> FOUR_KIB_SHIFT) % NUM_ENTRIES_4KIB,
+    )
+}
+
+fn output_addr(entry: u64) -> usize {
+    (entry & OUTPUT_ADDR_MASK) as usize
+}
+
+/// Forget the active address space, called when the kernel's tables are
+/// activated.
+pub(super) fn clear_current() {
+    CURRENT.lock(|c| *c = 0);
+}
+
+/// Resolve a fault at `virt_addr` in the active address space. On success, the
+/// faulting instruction can be retried.
+pub fn handle_fault(virt_addr: usize, kind: FaultKind, is_write: bool) -> Result<(), &'static str> {
+    let inner = CURRENT.lock(|c| *c);
+    if inner == 0 {
+        return Err("AddressSpace: No user address space active.");
+    }
+
+    let inner = unsafe { &mut *(inner as *mut Inner) };
+    match kind {
+        FaultKind::Translation => inner.handle_translation_fault(virt_addr),
+        FaultKind::Permission => inner.handle_permission_fault(virt_addr, is_write),
+    }
+}
+
+impl Inner {
+    fn vma(&self, virt_addr: usize) -> Option<Vma> {
+        self.vmas
+            .iter()
+            .filter_map(|v| *v)
+            .find(|v| v.contains(virt_addr))
+    }
+
+    /// The LVL3 entry for `virt_addr`, optionally allocating a missing LVL3
+    /// table.
+    fn lvl3_entry(
+        &mut self,
+        virt_addr: usize,
+        alloc: bool,
+    ) -> Result<&'static mut u64, &'static str> {
+        if !is_user_addr(virt_addr) {
+            return Err("AddressSpace: Address outside of user range.");
+        }
+
+        let (lvl2_idx, lvl3_idx) = indices(virt_addr);
+        let lvl2_table = unsafe { table(self.lvl2) };
+
+        if lvl2_table.entries[lvl2_idx] & VALID == 0 {
+            if !alloc {
+                return Err("AddressSpace: Page is not mapped.");
+            }
+
+            let lvl3 = alloc_frame(true)?;
+            lvl2_table.entries[lvl2_idx] = TableDescriptor::new(lvl3)?.value();
+        }
+
+        let lvl3 = output_addr(lvl2_table.entries[lvl2_idx]);
+
+        Ok(unsafe { &mut table(lvl3).entries[lvl3_idx] })
+    }
+
+    fn map_page(
+        &mut self,
+        virt_addr: usize,
+        output_addr: usize,
+        attribute_fields: AttributeFields,
+    ) -> Result<(), &'static str> {
+        if virt_addr % FOUR_KIB != 0 {
+            return Err("AddressSpace: Address is not 4 KiB aligned.");
+        }
+
+        let entry = self.lvl3_entry(virt_addr, true)?;
+        if *entry & VALID != 0 {
+            return Err("AddressSpace: Page is already mapped.");
+        }
+
+        *entry = PageDescriptor::new_user(output_addr, attribute_fields)?.value();
+
+        // Make the new entry visible to the table walker. No TLB maintenance is
+        // needed, because invalid entries are never cached.
+        unsafe {
+            barrier::dsb(barrier::SY);
+            barrier::isb(barrier::SY);
+        }
+
+        Ok(())
+    }
+
+    /// Back a page of a VMA with a zeroed frame.
+    fn handle_translation_fault(&mut self, virt_addr: usize) -> Result<(), &'static str> {
+        let vma = match self.vma(virt_addr) {
+            Some(v) => v,
+            None => return Err("AddressSpace: Fault outside of any VMA."),
+        };
+
+        let page = virt_addr & !(FOUR_KIB - 1);
+        let frame = alloc_frame(true)?;
+
+        self.map_page(page, frame, vma.attribute_fields)
+            .map_err(|s| {
+                free_frame(frame);
+                s
+            })
+    }
+
+    /// Break up sharing of a copy-on-write page on a write access.
+    fn handle_permission_fault(
+        &mut self,
+        virt_addr: usize,
+        is_write: bool,
+    ) -> Result<(), &'static str> {
+        let vma = match self.vma(virt_addr) {
+            Some(v) if is_write && v.is_writable() => v,
+            _ => return Err("AddressSpace: Access violates the VMA's permissions."),
+        };
+
+        let page = virt_addr & !(FOUR_KIB - 1);
+        let entry = self.lvl3_entry(page, false)?;
+        if *entry & SW_COW == 0 {
+            return Err("AddressSpace: Permission fault on a page that is not copy-on-write.");
+        }
+
+        let old_frame = output_addr(*entry);
+        if crate::FRAME_ALLOCATOR.lock(|f| f.refcount(old_frame)) == 1 {
+            // The last user of the frame, so it can just be taken over.
+            *entry &= !(AP_READ_ONLY | SW_COW);
+        } else {
+            let new_frame = alloc_frame(false)?;
+            unsafe {
+                ptr::copy_nonoverlapping(old_frame as *const u8, new_frame as *mut u8, FOUR_KIB)
+            };
+
+            *entry = PageDescriptor::new_user(new_frame, vma.attribute_fields)?.value();
+            free_frame(old_frame);
+        }
+
+        self.invalidate_page(page);
+
+        Ok(())
+    }
+
+    /// Drop a stale TLB entry of this address space.
+    fn invalidate_page(&self, virt_addr: usize) {
+        if !asid::is_current(&self.asid) {
+            return;
+        }
+
+        let operand = self.asid.tag() | (virt_addr >> FOUR_KIB_SHIFT) as u64;
+        unsafe {
+            barrier::dsb(barrier::SY);
+            asm!("tlbi vae1is, $0" :: "r"(operand) :: "volatile");
+            barrier::dsb(barrier::SY);
+            barrier::isb(barrier::SY);
+        }
+    }
+
+    /// Call `f` for every valid LVL3 entry, together with its virtual address.
+    fn for_each_page<F>(&mut self, mut f: F)
+    where
+        F: FnMut(usize, &mut u64),
+    {
+        let lvl2_table = unsafe { table(self.lvl2) };
+
+        for (lvl2_idx, lvl2_entry) in lvl2_table.entries.iter().enumerate() {
+            if lvl2_entry & VALID == 0 {
+                continue;
+            }
+
+            let lvl3_table = unsafe { table(output_addr(*lvl2_entry)) };
+            for (lvl3_idx, entry) in lvl3_table.entries.iter_mut().enumerate() {
+                if *entry & VALID == 0 {
+                    continue;
+                }
+
+                let virt_addr = map::virt::USER_START
+                    + (lvl2_idx << TWO_MIB_SHIFT)
+                    + (lvl3_idx << FOUR_KIB_SHIFT);
+                f(virt_addr, entry);
+            }
+        }
+    }
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let inner = alloc_frame(true)?;
+
+        let tables = alloc_frame(true).and_then(|lvl1| match alloc_frame(true) {
+            Ok(lvl2) => Ok((lvl1, lvl2)),
+            Err(s) => {
+                free_frame(lvl1);
+                Err(s)
+            }
+        });
+        let (lvl1, lvl2) = match tables {
+            Ok(t) => t,
+            Err(s) => {
+                free_frame(inner);
+                return Err(s);
+            }
+        };
+
+        unsafe {
+            let lvl1_table = table(lvl1);
+            lvl1_table.entries[0] = super::kernel_lvl1_entry();
+            lvl1_table.entries[1] = TableDescriptor::new(lvl2)?.value();
+
+            ptr::write(
+                inner as *mut Inner,
+                Inner {
+                    lvl1,
+                    lvl2,
+                    asid: asid::Asid::unallocated(),
+                    vmas: [None; MAX_VMAS],
+                },
+            );
+        }
+
+        Ok(AddressSpace { inner })
+    }
+
+    fn inner(&mut self) -> &mut Inner {
+        unsafe { &mut *(self.inner as *mut Inner) }
+    }
+
+    /// Map the 4 KiB page at `virt_addr` to `output_addr`. If `output_addr` is
+    /// a frame of the frame allocator, the address space takes over its
+    /// reference.
+    pub fn map_page(
+        &mut self,
+        virt_addr: usize,
+        output_addr: usize,
+        attribute_fields: AttributeFields,
+    ) -> Result<(), &'static str> {
+        self.inner()
+            .map_page(virt_addr, output_addr, attribute_fields)
+    }
+
+    /// Remove the mapping of the 4 KiB page at `virt_addr` and return the
+    /// address it was mapped to. The reference to the frame goes to the caller.
+    pub fn unmap_page(&mut self, virt_addr: usize) -> Option<usize> {
+        let inner = self.inner();
+        let entry = inner.lvl3_entry(virt_addr, false).ok()?;
+        if *entry & VALID == 0 {
+            return None;
+        }
+
+        let frame = output_addr(*entry);
+        *entry = 0;
+
+        inner.invalidate_page(virt_addr);
+
+        Some(frame)
+    }
+
+    /// Register an anonymous mapping of `size` bytes at `start`. Its pages are
+    /// backed with zeroed frames on first access.
+    pub fn map_anonymous(
+        &mut self,
+        start: usize,
+        size: usize,
+        attribute_fields: AttributeFields,
+    ) -> Result<(), &'static str> {
+        if start % FOUR_KIB != 0 || size % FOUR_KIB != 0 || size == 0 {
+            return Err("AddressSpace: VMA is not 4 KiB aligned.");
+        }
+
+        let end = start + size - 1;
+        if !is_user_addr(start) || !is_user_addr(end) {
+            return Err("AddressSpace: VMA outside of user range.");
+        }
+
+        let inner = self.inner();
+        let overlaps = inner
+            .vmas
+            .iter()
+            .filter_map(|v| *v)
+            .any(|v| v.start <= end && start <= v.end);
+        if overlaps {
+            return Err("AddressSpace: VMA overlaps an existing one.");
+        }
+
+        match inner.vmas.iter_mut().find(|v| v.is_none()) {
+            None => Err("AddressSpace: Too many VMAs."),
+            Some(slot) => {
+                *slot = Some(Vma {
+                    start,
+                    end,
+                    attribute_fields,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Create a copy of this address space. Frames are not copied, but shared.
+    /// Pages of writable VMAs become read-only copy-on-write pages in both
+    /// address spaces, and are copied on the first write.
+    pub fn fork(&mut self) -> Result<AddressSpace, &'static str> {
+        let mut child = AddressSpace::new()?;
+        let parent = self.inner();
+        child.inner().vmas = parent.vmas;
+
+        let mut result = Ok(());
+        let vmas = parent.vmas;
+        parent.for_each_page(|virt_addr, entry| {
+            if result.is_err() {
+                return;
+            }
+
+            let is_writable = vmas
+                .iter()
+                .filter_map(|v| *v)
+                .find(|v| v.contains(virt_addr))
+                .map_or(false, |v| v.is_writable());
+            if is_writable {
+                *entry |= AP_READ_ONLY | SW_COW;
+            }
+
+            let frame = output_addr(*entry);
+            if crate::FRAME_ALLOCATOR.lock(|f| f.share(frame)).is_err() {
+                result = Err("AddressSpace: Frame can not be shared.");
+                return;
+            }
+
+            match child.inner().lvl3_entry(virt_addr, true) {
+                Ok(child_entry) => *child_entry = *entry,
+                Err(s) => {
+                    free_frame(frame);
+                    result = Err(s);
+                }
+            }
+        });
+
+        // The parent's pages may have turned read-only.
+        asid::invalidate(&parent.asid);
+        unsafe { barrier::dsb(barrier::SY) };
+
+        result.map(|_| child)
+    }
+
+    fn is_active(&self) -> bool {
+        CURRENT.lock(|c| *c == self.inner)
+    }
+
+    /// Switch to this address space by pointing TTBR0 to its tables.
+    pub fn activate(&mut self) {
+        let inner = self.inner();
+        asid::refresh(&mut inner.asid);
+
+        TTBR0_EL1.set(inner.lvl1 as u64 | inner.asid.tag());
+        unsafe { barrier::isb(barrier::SY) };
+
+        CURRENT.lock(|c| *c = self.inner);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            super::activate_kernel_tables();
+        }
+
+        let inner = self.inner();
+        asid::invalidate(&inner.asid);
+
+        inner.for_each_page(|_, entry| free_frame(output_addr(*entry)));
+
+        let lvl2_table = unsafe { table(inner.lvl2) };
+        for entry in lvl2_table.entries.iter().filter(|e| *e & VALID != 0) {
+            free_frame(output_addr(*entry));
+        }
+
+        free_frame(inner.lvl2);
+        free_frame(inner.lvl1);
+        free_frame(self.inner);
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Allocation of Address Space IDs.
+//!
+//! ASIDs are handed out round-robin. When they run out, a new generation is
+//! started and the whole TLB is flushed once. Address spaces with an ASID from
+//! an old generation get a fresh one the next time they are activated. This
+//! way, switching address spaces never needs a TLB flush of its own.
+
+use crate::sync::NullLock;
+use cortex_a::{barrier, regs::*};
+
+/// TCR_EL1.AS: Use 16 bit ASIDs.
+const TCR_EL1_AS: u64 = 1 << 36;
+
+/// ID_AA64MMFR0_EL1.ASIDBits value for 16 bit support.
+const ASID_BITS_16: u64 = 0b0010;
+
+/// ASID 0 belongs to the kernel's tables.
+const FIRST_USER_ASID: u64 = 1;
+
+#[derive(Copy, Clone)]
+pub struct Asid {
+    generation: u64,
+    value: u16,
+}
+
+impl Asid {
+    /// An ASID that is never valid, forcing allocation on first use.
+    pub const fn unallocated() -> Asid {
+        Asid {
+            generation: 0,
+            value: 0,
+        }
+    }
+
+    /// The ASID as it is encoded in TTBR0_EL1 and TLBI operands.
+    pub fn tag(&self) -> u64 {
+        u64::from(self.value) << 48
+    }
+}
+
+struct AsidAllocator {
+    bits: u32,
+    generation: u64,
+    next: u64,
+}
+
+static ALLOCATOR: NullLock<AsidAllocator> = NullLock::new(AsidAllocator {
+    bits: 8,
+    generation: 1,
+    next: FIRST_USER_ASID,
+});
+
+/// Select the ASID size depending on what the CPU implements.
+pub fn init() {
+    let asid_bits = (ID_AA64MMFR0_EL1.get() >> 4) & 0xF;
+
+    if asid_bits == ASID_BITS_16 {
+        TCR_EL1.set(TCR_EL1.get() | TCR_EL1_AS);
+        ALLOCATOR.lock(|a| a.bits = 16);
+    }
+}
+
+/// Check if `asid` belongs to the current generation.
+pub fn is_current(asid: &Asid) -> bool {
+    ALLOCATOR.lock(|a| asid.generation == a.generation)
+}
+
+/// Make sure `asid` belongs to the current generation, allocating a new one if
+/// needed.
+pub fn refresh(asid: &mut Asid) {
+    ALLOCATOR.lock(|a| {
+        if asid.generation == a.generation {
+            return;
+        }
+
+        if a.next == 1 << a.bits {
+            // Rollover. Nothing may use an old ASID while the TLB is flushed,
+            // so fall back to the kernel's tables first.
+            super::activate_kernel_tables();
+            flush_all();
+
+            a.generation += 1;
+            a.next = FIRST_USER_ASID;
+        }
+
+        *asid = Asid {
+            generation: a.generation,
+            value: a.next as u16,
+        };
+        a.next += 1;
+    })
+}
+
+/// Drop all TLB entries tagged with `asid`.
+pub fn invalidate(asid: &Asid) {
+    if !is_current(asid) {
+        // Already gone with the last rollover.
+        return;
+    }
+
+    unsafe {
+        barrier::dsb(barrier::SY);
+        asm!("tlbi aside1is, $0" :: "r"(asid.tag()) :: "volatile");
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+}
+
+/// Drop all EL1&0 TLB entries.
+fn flush_all() {
+    unsafe {
+        barrier::dsb(barrier::SY);
+        asm!("tlbi vmalle1is" :::: "volatile");
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Power domains of the devices, switched by the firmware.
+
+use crate::devices::hw::videocore_mbox::{tag, VideocoreMbox};
+
+#[derive(Debug)]
+pub enum PowerError {
+    MailboxError,
+
+    /// The firmware does not know the device.
+    NoSuchDevice,
+
+    /// The device did not reach the requested state.
+    StateNotReached,
+
+    /// The global mailbox is not set up yet.
+    NoMailbox,
+}
+pub type Result<T> = ::core::result::Result<T, PowerError>;
+
+/// The firmware power domain IDs.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Domain {
+    SdCard = 0,
+    Uart0 = 1,
+    Uart1 = 2,
+    UsbHcd = 3,
+    I2c0 = 4,
+    I2c1 = 5,
+    I2c2 = 6,
+    Spi = 7,
+    Ccp2tx = 8,
+}
+
+impl Domain {
+    pub const ALL: [Domain; 9] = [
+        Domain::SdCard,
+        Domain::Uart0,
+        Domain::Uart1,
+        Domain::UsbHcd,
+        Domain::I2c0,
+        Domain::I2c1,
+        Domain::I2c2,
+        Domain::Spi,
+        Domain::Ccp2tx,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Domain::SdCard => "SD card",
+            Domain::Uart0 => "UART0",
+            Domain::Uart1 => "UART1",
+            Domain::UsbHcd => "USB HCD",
+            Domain::I2c0 => "I2C0",
+            Domain::I2c1 => "I2C1",
+            Domain::I2c2 => "I2C2",
+            Domain::Spi => "SPI",
+            Domain::Ccp2tx => "CCP2TX",
+        }
+    }
+
+    /// Whether the device is powered.
+    pub fn is_on(self, v_mbox: &mut VideocoreMbox) -> Result<bool> {
+        let state = v_mbox
+            .query(tag::GetPowerState {
+                device: self as u32,
+            })
+            .map_err(|_| PowerError::MailboxError)?;
+
+        if !state.exists {
+            return Err(PowerError::NoSuchDevice);
+        }
+
+        Ok(state.on)
+    }
+
+    /// Switch the power of the device and wait until it is stable.
+    pub fn set(self, v_mbox: &mut VideocoreMbox, on: bool) -> Result<()> {
+        let state = v_mbox
+            .query(tag::SetPowerState {
+                device: self as u32,
+                on,
+                wait: true,
+            })
+            .map_err(|_| PowerError::MailboxError)?;
+
+        if !state.exists {
+            return Err(PowerError::NoSuchDevice);
+        }
+
+        if state.on != on {
+            return Err(PowerError::StateNotReached);
+        }
+
+        Ok(())
+    }
+
+    /// Switch the power of the device through the global mailbox.
+    pub fn set_shared(self, on: bool) -> Result<()> {
+        crate::MBOX.lock(|m| match m.as_mut() {
+            Some(v_mbox) => self.set(v_mbox, on),
+            None => Err(PowerError::NoMailbox),
+        })
+    }
+}
+
+/// Power off all domains but the ones in `except`. Returns the domains that
+/// failed, with the reason.
+pub fn all_off(v_mbox: &mut VideocoreMbox, except: &[Domain]) -> [Option<PowerError>; 9] {
+    let mut failed = [None, None, None, None, None, None, None, None, None];
+
+    for (i, domain) in Domain::ALL.iter().enumerate() {
+        if except.contains(domain) {
+            continue;
+        }
+
+        match domain.set(v_mbox, false) {
+            // Not every board has every device.
+            Ok(()) | Err(PowerError::NoSuchDevice) => (),
+            Err(e) => failed[i] = Some(e),
+        }
+    }
+
+    failed
+}
@@ -0,0 +1,752 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A small shell for poking at the board over the serial console.
+//!
+//! Commands live in a static table. To add one, write a handler and add an
+//! entry to `COMMANDS`.
+
+use crate::devices::hw::{self, VideocoreMbox};
+use crate::devices::virt::ConsoleOps;
+use crate::memory::{self, map};
+use crate::{board, clocks, idle, log, power, print, println, thermal, timer};
+
+/// Most words a command line can be split into, including the command name.
+const MAX_ARGS: usize = 8;
+
+/// Limits of `peek` and `hexdump`, so that a typo does not flood the line.
+const MAX_PEEK_WORDS: usize = 64;
+const MAX_HEXDUMP_BYTES: usize = 4096;
+
+pub enum Error {
+    /// The arguments did not make sense. The usage is printed.
+    Usage,
+    Failed(&'static str),
+}
+
+impl From<&'static str> for Error {
+    fn from(s: &'static str) -> Error {
+        Error::Failed(s)
+    }
+}
+
+type Result = ::core::result::Result<(), Error>;
+
+pub struct Command {
+    pub name: &'static str,
+
+    /// The arguments, e.g. "<addr> [count]".
+    pub usage: &'static str,
+    pub help: &'static str,
+
+    /// Called with the arguments after the command name.
+    pub handler: fn(&[&str]) -> Result,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "[command]",
+        help: "List commands, or show the usage of one",
+        handler: help,
+    },
+    Command {
+        name: "layout",
+        usage: "",
+        help: "Print the kernel memory layout",
+        handler: layout,
+    },
+    Command {
+        name: "peek",
+        usage: "<addr> [words]",
+        help: "Read 32 bit words from memory or MMIO",
+        handler: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "<addr> <value>",
+        help: "Write a 32 bit word to memory or MMIO",
+        handler: poke,
+    },
+    Command {
+        name: "hexdump",
+        usage: "<addr> [bytes]",
+        help: "Dump memory as hex and ASCII",
+        handler: hexdump,
+    },
+    Command {
+        name: "reboot",
+        usage: "[partition]",
+        help: "Reboot, optionally into another boot partition",
+        handler: reboot,
+    },
+    Command {
+        name: "halt",
+        usage: "",
+        help: "Halt the SoC until the power is cycled, devices stay on",
+        handler: halt,
+    },
+    Command {
+        name: "poweroff",
+        usage: "",
+        help: "Power off all devices and halt",
+        handler: poweroff,
+    },
+    Command {
+        name: "power",
+        usage: "",
+        help: "Print the power state of the devices",
+        handler: power,
+    },
+    Command {
+        name: "watchdog",
+        usage: "<timeout_ms>|stop",
+        help: "Arm the watchdog with a keep-alive callback, or stop it",
+        handler: watchdog,
+    },
+    Command {
+        name: "uptime",
+        usage: "",
+        help: "Time since boot",
+        handler: uptime,
+    },
+    Command {
+        name: "board",
+        usage: "",
+        help: "Print the board information",
+        handler: board,
+    },
+    Command {
+        name: "dmesg",
+        usage: "",
+        help: "Print the kernel log",
+        handler: dmesg,
+    },
+    Command {
+        name: "loglevel",
+        usage: "[module] [error|warn|info|debug|trace]",
+        help: "Show or set the log level, of all or one module",
+        handler: loglevel,
+    },
+    Command {
+        name: "rand",
+        usage: "[min max]",
+        help: "Print a number from the hardware RNG",
+        handler: rand,
+    },
+    Command {
+        name: "clocks",
+        usage: "[turbo on|off]",
+        help: "Print the clock rates, or switch turbo mode",
+        handler: clocks,
+    },
+    Command {
+        name: "temp",
+        usage: "[performance|powersave|ondemand]",
+        help: "Print temperature and throttling state, or set the policy",
+        handler: temp,
+    },
+    Command {
+        name: "serial",
+        usage: "[baud [8N1] [rtscts16|rtscts30]]",
+        help: "Print the UART error counters, or reconfigure the PL011 UART",
+        handler: serial,
+    },
+    Command {
+        name: "fb",
+        usage: "[demo]",
+        help: "Print the framebuffer console size, or show a double buffered demo",
+        handler: fb,
+    },
+];
+
+/// Split `line` into words and run the command they name.
+pub fn dispatch(line: &str) {
+    let mut words = [""; MAX_ARGS];
+    let mut argc = 0;
+
+    // Show what IRQ handlers logged while waiting for input.
+    log::replay();
+
+    for word in line.split_whitespace() {
+        if argc == MAX_ARGS {
+            println!("Too many arguments.");
+            return;
+        }
+
+        words[argc] = word;
+        argc += 1;
+    }
+
+    if argc == 0 {
+        return;
+    }
+
+    let cmd = match COMMANDS.iter().find(|c| c.name == words[0]) {
+        Some(cmd) => cmd,
+        None => {
+            println!("{}: command not found. Try 'help'.", words[0]);
+            return;
+        }
+    };
+
+    match (cmd.handler)(&words[1..argc]) {
+        Ok(()) => (),
+        Err(Error::Usage) => println!("usage: {} {}", cmd.name, cmd.usage),
+        Err(Error::Failed(s)) => println!("{}: {}", cmd.name, s),
+    }
+}
+
+/// Parse a number, in hex if it starts with "0x".
+fn parse(s: &str) -> ::core::result::Result<usize, Error> {
+    let ret = if s.starts_with("0x") || s.starts_with("0X") {
+        usize::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+
+    ret.map_err(|_| Error::Usage)
+}
+
+/// Parse a word address that the kernel can access.
+fn parse_addr(s: &str, write: bool) -> ::core::result::Result<usize, Error> {
+    let addr = parse(s)?;
+
+    if addr % 4 != 0 {
+        return Err(Error::Failed("Address must be 4 byte aligned."));
+    }
+    memory::check_kernel_access(addr, write)?;
+
+    Ok(addr)
+}
+
+/// Read a word, after checking that it is accessible.
+fn read_word(addr: usize) -> ::core::result::Result<u32, Error> {
+    memory::check_kernel_access(addr, false)?;
+
+    Ok(unsafe { core::ptr::read_volatile(addr as *const u32) })
+}
+
+fn with_mbox<F>(f: F) -> Result
+where
+    F: FnOnce(&mut VideocoreMbox) -> Result,
+{
+    crate::MBOX.lock(|m| match m.as_mut() {
+        Some(v_mbox) => f(v_mbox),
+        None => Err(Error::Failed("Mailbox not available.")),
+    })
+}
+
+fn help(args: &[&str]) -> Result {
+    match args {
+        [] => {
+            for cmd in COMMANDS.iter() {
+                println!("  {:<9} {}", cmd.name, cmd.help);
+            }
+
+            Ok(())
+        }
+        [name] => match COMMANDS.iter().find(|c| c.name == *name) {
+            Some(cmd) => {
+                println!("{}\nusage: {} {}", cmd.help, cmd.name, cmd.usage);
+
+                Ok(())
+            }
+            None => Err(Error::Failed("No such command.")),
+        },
+        _ => Err(Error::Usage),
+    }
+}
+
+fn layout(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+
+    memory::print_layout();
+
+    Ok(())
+}
+
+fn peek(args: &[&str]) -> Result {
+    let (addr, words) = match args {
+        [addr] => (parse_addr(addr, false)?, 1),
+        [addr, words] => (parse_addr(addr, false)?, parse(words)?),
+        _ => return Err(Error::Usage),
+    };
+
+    if words == 0 || words > MAX_PEEK_WORDS {
+        return Err(Error::Failed("Can read 1 to 64 words."));
+    }
+
+    for i in 0..words {
+        let a = addr + 4 * i;
+
+        if i % 4 == 0 {
+            if i != 0 {
+                println!();
+            }
+            print!("{:#010X}:", a);
+        }
+        print!(" {:08X}", read_word(a)?);
+    }
+    println!();
+
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result {
+    let (addr, value) = match args {
+        [addr, value] => (parse_addr(addr, true)?, parse(value)?),
+        _ => return Err(Error::Usage),
+    };
+
+    if value > u32::max_value() as usize {
+        return Err(Error::Failed("Value does not fit into 32 bits."));
+    }
+
+    unsafe { core::ptr::write_volatile(addr as *mut u32, value as u32) };
+
+    Ok(())
+}
+
+fn hexdump(args: &[&str]) -> Result {
+    let (addr, len) = match args {
+        [addr] => (parse_addr(addr, false)?, 256),
+        [addr, len] => (parse_addr(addr, false)?, parse(len)?),
+        _ => return Err(Error::Usage),
+    };
+
+    if len == 0 || len > MAX_HEXDUMP_BYTES {
+        return Err(Error::Failed("Can dump 1 to 4096 bytes."));
+    }
+
+    // Read whole words only, MMIO does not like byte accesses. Nothing past
+    // the word that holds the last byte is read, reads may have side
+    // effects.
+    let mut line = [0u8; 16];
+    for offset in (0..len).step_by(16) {
+        let n = (len - offset).min(16);
+
+        for w in 0..(n + 3) / 4 {
+            let word = read_word(addr + offset + 4 * w)?;
+            line[4 * w..4 * w + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        print!("{:#010X}: ", addr + offset);
+        for (i, b) in line.iter().enumerate() {
+            if i < n {
+                print!("{:02X} ", b);
+            } else {
+                print!("   ");
+            }
+        }
+
+        print!(" |");
+        for b in line[..n].iter() {
+            let c = if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            };
+            print!("{}", c);
+        }
+        println!("|");
+    }
+
+    Ok(())
+}
+
+fn reboot(args: &[&str]) -> Result {
+    let partition = match args {
+        [] => None,
+        [partition] => {
+            let p = parse(partition)?;
+            if p > u32::max_value() as usize {
+                return Err(Error::Failed("Invalid partition number."));
+            }
+
+            Some(hw::Partition::new(p as u32)?)
+        }
+        _ => return Err(Error::Usage),
+    };
+
+    let power = hw::Power::new(map::physical::POWER_BASE);
+
+    println!("Rebooting...");
+    crate::CONSOLE.lock(|c| c.flush());
+
+    match partition {
+        None => power.reset(),
+        Some(p) => power.reboot_to_partition(p),
+    }
+}
+
+fn halt(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+
+    let power = hw::Power::new(map::physical::POWER_BASE);
+
+    println!("Halting...");
+    crate::CONSOLE.lock(|c| c.flush());
+
+    power.halt();
+}
+
+fn poweroff(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+
+    with_mbox(|v_mbox| {
+        let power = hw::Power::new(map::physical::POWER_BASE);
+        let gpio = hw::GPIO::new(map::physical::GPIO_BASE);
+
+        println!("Powering off...");
+        crate::CONSOLE.lock(|c| c.flush());
+
+        power.off(v_mbox, &gpio);
+    })
+}
+
+fn power(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+
+    with_mbox(|v_mbox| {
+        for domain in power::Domain::ALL.iter() {
+            let state = match domain.is_on(v_mbox) {
+                Ok(true) => "on",
+                Ok(false) => "off",
+                Err(power::PowerError::NoSuchDevice) => "not present",
+                Err(_) => "unknown",
+            };
+
+            println!("  {:<9} {}", domain.name(), state);
+        }
+
+        Ok(())
+    })
+}
+
+fn watchdog(args: &[&str]) -> Result {
+    let watchdog = hw::Watchdog::new(map::physical::POWER_BASE);
+
+    match args {
+        ["stop"] => watchdog.stop(),
+        [timeout_ms] => {
+            let timeout_ms = parse(timeout_ms)? as u64;
+            if timeout_ms == 0 || timeout_ms > hw::Watchdog::MAX_TIMEOUT_MS {
+                return Err(Error::Failed("Timeout out of range."));
+            }
+
+            watchdog.start_with_keep_alive(timeout_ms)?;
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn uptime(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+
+    let us = timer::uptime_us();
+    let secs = us / 1_000_000;
+
+    println!(
+        "up {}d {:02}:{:02}:{:02}.{:03}",
+        secs / 86400,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        us / 1000 % 1000
+    );
+
+    Ok(())
+}
+
+fn board(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+
+    match board::info() {
+        Some(info) => info.print(),
+        None => return Err(Error::Failed("No board information.")),
+    }
+
+    Ok(())
+}
+
+fn dmesg(args: &[&str]) -> Result {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+
+    log::dmesg();
+
+    Ok(())
+}
+
+fn parse_level(s: &str) -> ::core::result::Result<log::Level, Error> {
+    log::Level::ALL
+        .iter()
+        .find(|l| l.name().eq_ignore_ascii_case(s))
+        .cloned()
+        .ok_or(Error::Usage)
+}
+
+fn loglevel(args: &[&str]) -> Result {
+    match args {
+        [] => println!("{}", log::default_level().name()),
+        [level] => log::set_default_level(parse_level(level)?),
+        [module, level] => log::set_level(module, parse_level(level)?)?,
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn rand(args: &[&str]) -> Result {
+    let (min, max) = match args {
+        [] => (0, u32::max_value() as usize),
+        [min, max] => (parse(min)?, parse(max)?),
+        _ => return Err(Error::Usage),
+    };
+
+    if min > max || max > u32::max_value() as usize {
+        return Err(Error::Failed("Need min <= max < 2^32."));
+    }
+
+    let rng = hw::Rng::new(map::physical::RNG_BASE);
+    rng.init();
+
+    println!("{}", rng.rand(min as u32, max as u32));
+
+    Ok(())
+}
+
+fn clocks(args: &[&str]) -> Result {
+    let turbo = match args {
+        [] => None,
+        ["turbo", "on"] => Some(true),
+        ["turbo", "off"] => Some(false),
+        _ => return Err(Error::Usage),
+    };
+
+    with_mbox(|v_mbox| match turbo {
+        Some(on) => {
+            clocks::set_turbo(v_mbox, on).map_err(|_| Error::Failed("Mailbox call failed."))
+        }
+        None => {
+            clocks::print(v_mbox);
+            Ok(())
+        }
+    })
+}
+
+fn temp(args: &[&str]) -> Result {
+    match args {
+        [] => (),
+        [policy] => {
+            let policy = thermal::Policy::ALL
+                .iter()
+                .find(|p| p.name() == *policy)
+                .cloned()
+                .ok_or(Error::Usage)?;
+
+            thermal::set_policy(policy);
+            return Ok(());
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    with_mbox(|v_mbox| {
+        thermal::print_status(v_mbox).map_err(|_| Error::Failed("Mailbox call failed."))
+    })
+}
+
+/// Parse a line format like "8N1" or "7E1".
+fn parse_format(
+    s: &str,
+) -> ::core::result::Result<(hw::DataBits, hw::Parity, hw::StopBits), Error> {
+    let b = s.as_bytes();
+    if b.len() != 3 {
+        return Err(Error::Usage);
+    }
+
+    let data_bits = match b[0] {
+        b'5' => hw::DataBits::Five,
+        b'6' => hw::DataBits::Six,
+        b'7' => hw::DataBits::Seven,
+        b'8' => hw::DataBits::Eight,
+        _ => return Err(Error::Usage),
+    };
+    let parity = match b[1] {
+        b'N' | b'n' => hw::Parity::None,
+        b'E' | b'e' => hw::Parity::Even,
+        b'O' | b'o' => hw::Parity::Odd,
+        _ => return Err(Error::Usage),
+    };
+    let stop_bits = match b[2] {
+        b'1' => hw::StopBits::One,
+        b'2' => hw::StopBits::Two,
+        _ => return Err(Error::Usage),
+    };
+
+    Ok((data_bits, parity, stop_bits))
+}
+
+fn parse_flow_control(s: &str) -> ::core::result::Result<hw::FlowControl, Error> {
+    match s {
+        "rtscts16" => Ok(hw::FlowControl::RtsCts(hw::FlowControlPins::Gpio16And17)),
+        "rtscts30" => Ok(hw::FlowControl::RtsCts(hw::FlowControlPins::Gpio30And31)),
+        _ => Err(Error::Usage),
+    }
+}
+
+fn serial(args: &[&str]) -> Result {
+    if args.is_empty() {
+        let e = crate::PL011_UART.error_counters();
+        println!(
+            "PL011:    overrun {}, break {}, parity {}, framing {}, dropped {}",
+            e.overrun, e.breaks, e.parity, e.framing, e.dropped
+        );
+
+        let e = crate::MINI_UART.error_counters();
+        println!("MiniUart: overrun {}, dropped {}", e.overrun, e.dropped);
+
+        return Ok(());
+    }
+
+    if args.len() > 3 {
+        return Err(Error::Usage);
+    }
+
+    let baud = parse(args[0])?;
+    if baud == 0 || baud > u32::max_value() as usize {
+        return Err(Error::Usage);
+    }
+
+    let mut config = hw::SerialConfig::default();
+    config.baud = baud as u32;
+
+    for arg in args[1..].iter() {
+        if arg.starts_with("rtscts") {
+            config.flow_control = parse_flow_control(arg)?;
+        } else {
+            let (data_bits, parity, stop_bits) = parse_format(arg)?;
+            config.data_bits = data_bits;
+            config.parity = parity;
+            config.stop_bits = stop_bits;
+        }
+    }
+
+    with_mbox(|v_mbox| {
+        let gpio = hw::GPIO::new(map::physical::GPIO_BASE);
+
+        // Whatever is still queued would go out with the new settings.
+        crate::CONSOLE.lock(|c| c.flush());
+
+        crate::PL011_UART
+            .init(v_mbox, &gpio, &config)
+            .map_err(|_| Error::Failed("PL011 UART init failed."))
+    })
+}
+
+fn fb(args: &[&str]) -> Result {
+    match args {
+        [] => {
+            let (cols, rows) = crate::FB_CONSOLE.size();
+            if cols == 0 {
+                return Err(Error::Failed("No framebuffer attached."));
+            }
+
+            println!("Console: {}x{} characters", cols, rows);
+            Ok(())
+        }
+        ["demo"] => with_mbox(fb_demo),
+        _ => Err(Error::Usage),
+    }
+}
+
+/// Take the framebuffer from the console for a double buffered animation.
+/// The console gets a new one afterwards, as there can only be one.
+fn fb_demo(v_mbox: &mut VideocoreMbox) -> Result {
+    let failed = |_| Error::Failed("Framebuffer call failed.");
+
+    let fb = crate::FB_CONSOLE
+        .detach()
+        .ok_or(Error::Failed("No framebuffer attached."))?;
+    let (width, height) = (fb.width() as u32, fb.height() as u32);
+    fb.release(v_mbox).map_err(failed)?;
+
+    let ret = match hw::Framebuffer::new(v_mbox, width, height, true) {
+        Ok(mut fb) => {
+            let ret = fb_animate(&mut fb, v_mbox);
+            ret.and(fb.release(v_mbox).map_err(failed))
+        }
+        Err(_) => Err(Error::Failed("No double buffered framebuffer granted.")),
+    };
+
+    let fb = hw::Framebuffer::new(v_mbox, width, height, false).map_err(failed)?;
+    crate::FB_CONSOLE.attach(fb);
+
+    ret
+}
+
+/// Move a square across the screen for about two seconds.
+fn fb_animate(fb: &mut hw::Framebuffer, v_mbox: &mut VideocoreMbox) -> Result {
+    const FRAMES: usize = 120;
+    const FRAME_US: u64 = 16_667;
+    const SQUARE: usize = 32;
+
+    let (width, height) = (fb.width(), fb.height());
+    let mut next = timer::uptime_us();
+
+    for frame in 0..FRAMES {
+        fb.clear(hw::Color::BLACK);
+        for i in 0..width.min(height) {
+            fb.set_pixel(i, i, hw::Color::WHITE);
+        }
+
+        let x = frame * width.saturating_sub(SQUARE) / FRAMES;
+        let y = height.saturating_sub(SQUARE) / 2;
+        fb.fill_rect(x, y, SQUARE, SQUARE, hw::Color::rgb(0xFF, 0, 0));
+
+        fb.swap(v_mbox)
+            .map_err(|_| Error::Failed("Framebuffer call failed."))?;
+
+        next += FRAME_US;
+        idle::wait_until(|| timer::uptime_us() >= next);
+    }
+
+    Ok(())
+}
@@ -49,3 +49,35 @@ impl<T> NullLock<T> {
         f(unsafe { &mut *self.data.get() })
     }
 }
+
+/// A NullLock that additionally masks IRQs on the executing core while the
+/// closure runs.
+///
+/// Use it for data that is shared with interrupt handlers, so that a handler
+/// can never observe the data in the middle of an update.
+pub struct IrqSafeNullLock<T> {
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for IrqSafeNullLock<T> {}
+
+impl<T> IrqSafeNullLock<T> {
+    pub const fn new(data: T) -> IrqSafeNullLock<T> {
+        IrqSafeNullLock {
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T> IrqSafeNullLock<T> {
+    pub fn lock<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let daif = crate::interrupt::local_irq_save();
+        let ret = f(unsafe { &mut *self.data.get() });
+        crate::interrupt::local_irq_restore(daif);
+
+        ret
+    }
+}
@@ -0,0 +1,271 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Thermal monitoring and CPU frequency scaling.
+//!
+//! A periodic timer callback defers polling to thread context. Polling reads
+//! the throttling flags of the firmware and reports changes, and sets the ARM
+//! clock according to the active policy.
+
+use crate::clocks::{self, Clock};
+use crate::devices::hw::videocore_mbox::{self, tag, VideocoreMbox};
+use crate::{idle, info, println, sync::IrqSafeNullLock, timer, warn};
+
+const POLL_PERIOD_US: u64 = 1_000_000;
+
+/// Busy percentage above which the ondemand policy goes to the max rate.
+const UP_THRESHOLD: u64 = 80;
+
+/// The ondemand policy picks rates in steps of this size, so that small load
+/// changes do not cause a clock change on every poll.
+const STEP_HZ: u64 = 100_000_000;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Policy {
+    /// Always run at the max rate.
+    Performance,
+
+    /// Always run at the min rate.
+    Powersave,
+
+    /// Follow the load, measured as the time not spent idle.
+    Ondemand,
+}
+
+impl Policy {
+    pub const ALL: [Policy; 3] = [Policy::Performance, Policy::Powersave, Policy::Ondemand];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::Performance => "performance",
+            Policy::Powersave => "powersave",
+            Policy::Ondemand => "ondemand",
+        }
+    }
+}
+
+/// The throttling flags of the firmware.
+///
+/// The lower half holds the conditions that are active now, the upper half
+/// the conditions that occurred since boot.
+#[derive(Copy, Clone)]
+pub struct Throttled(pub u32);
+
+impl Throttled {
+    const CONDITIONS: [(u32, &'static str); 4] = [
+        (1 << 0, "under-voltage"),
+        (1 << 1, "ARM frequency capped"),
+        (1 << 2, "throttled"),
+        (1 << 3, "soft temperature limit"),
+    ];
+
+    fn now(self) -> u32 {
+        self.0 & 0xF
+    }
+
+    fn since_boot(self) -> u32 {
+        (self.0 >> 16) & 0xF
+    }
+}
+
+struct State {
+    policy: Policy,
+    min_hz: u32,
+    max_hz: u32,
+
+    /// The rate asked for last. The firmware may have set a different one.
+    requested_hz: u32,
+
+    /// Conditions active at the last poll.
+    throttled: u32,
+
+    /// Uptime and idle time at the last poll.
+    last_uptime_us: u64,
+    last_idle_us: u64,
+}
+
+static STATE: IrqSafeNullLock<State> = IrqSafeNullLock::new(State {
+    policy: Policy::Ondemand,
+    min_hz: 0,
+    max_hz: 0,
+    requested_hz: 0,
+    throttled: 0,
+    last_uptime_us: 0,
+    last_idle_us: 0,
+});
+
+/// SoC temperature in thousandths of a degree Celsius.
+pub fn temperature(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<u32> {
+    v_mbox.query(tag::GetTemperature)
+}
+
+/// Temperature at which the firmware starts throttling.
+pub fn max_temperature(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<u32> {
+    v_mbox.query(tag::GetMaxTemperature)
+}
+
+pub fn throttled(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<Throttled> {
+    Ok(Throttled(v_mbox.query(tag::GetThrottled)?))
+}
+
+/// Report conditions that came up or went away.
+fn report(old: u32, new: u32) {
+    for (bit, name) in Throttled::CONDITIONS.iter() {
+        if new & bit != 0 && old & bit == 0 {
+            warn!("{} detected.", name);
+        } else if new & bit == 0 && old & bit != 0 {
+            info!("{} cleared.", name);
+        }
+    }
+}
+
+/// The ARM clock rate that `policy` asks for.
+fn target_hz(s: &State, busy_percent: u64) -> u32 {
+    match s.policy {
+        Policy::Performance => s.max_hz,
+        Policy::Powersave => s.min_hz,
+        Policy::Ondemand => {
+            if busy_percent >= UP_THRESHOLD {
+                s.max_hz
+            } else {
+                let hz = u64::from(s.max_hz) * busy_percent / UP_THRESHOLD;
+                let hz = (hz + STEP_HZ - 1) / STEP_HZ * STEP_HZ;
+
+                (hz.min(u64::from(s.max_hz)) as u32).max(s.min_hz)
+            }
+        }
+    }
+}
+
+fn update(v_mbox: &mut VideocoreMbox) {
+    if let Ok(t) = throttled(v_mbox) {
+        let old = STATE.lock(|s| core::mem::replace(&mut s.throttled, t.now()));
+        report(old, t.now());
+    }
+
+    // Throttling changes clocks behind our back.
+    clocks::refresh(v_mbox);
+
+    let uptime_us = timer::uptime_us();
+    let idle_us = idle::idle_us();
+
+    let (target, requested) = STATE.lock(|s| {
+        let elapsed = (uptime_us - s.last_uptime_us).max(1);
+        let idle = (idle_us - s.last_idle_us).min(elapsed);
+        let busy_percent = 100 - idle * 100 / elapsed;
+
+        s.last_uptime_us = uptime_us;
+        s.last_idle_us = idle_us;
+
+        (target_hz(s, busy_percent), s.requested_hz)
+    });
+
+    if target == requested {
+        return;
+    }
+
+    if clocks::set_rate(v_mbox, Clock::Arm, target, false).is_ok() {
+        STATE.lock(|s| s.requested_hz = target);
+    }
+}
+
+fn poll() {
+    crate::MBOX.lock(|m| {
+        if let Some(v_mbox) = m.as_mut() {
+            update(v_mbox);
+        }
+    });
+}
+
+/// Called from the timer interrupt. Mailbox calls wait for the firmware, and
+/// a clock change reprograms the UARTs, so neither happens with IRQs masked.
+fn request_poll(_: usize) {
+    let _ = idle::defer(poll);
+}
+
+/// Read the ARM clock limits and start polling.
+pub fn init(v_mbox: &mut VideocoreMbox) -> Result<(), &'static str> {
+    let err = "Mailbox query failed.";
+
+    let min_hz = clocks::min_rate(v_mbox, Clock::Arm).map_err(|_| err)?;
+    let max_hz = clocks::max_rate(v_mbox, Clock::Arm).map_err(|_| err)?;
+    let hz = clocks::rate(v_mbox, Clock::Arm).map_err(|_| err)?;
+    let t = throttled(v_mbox).map_err(|_| err)?;
+
+    for (bit, name) in Throttled::CONDITIONS.iter() {
+        if t.since_boot() & bit != 0 {
+            warn!("{} occurred since boot.", name);
+        }
+    }
+    report(0, t.now());
+
+    STATE.lock(|s| {
+        s.min_hz = min_hz;
+        s.max_hz = max_hz;
+        s.requested_hz = hz;
+        s.throttled = t.now();
+        s.last_uptime_us = timer::uptime_us();
+        s.last_idle_us = idle::idle_us();
+    });
+
+    timer::add_periodic(POLL_PERIOD_US, request_poll, 0)?;
+
+    Ok(())
+}
+
+pub fn set_policy(policy: Policy) {
+    STATE.lock(|s| s.policy = policy);
+}
+
+pub fn policy() -> Policy {
+    STATE.lock(|s| s.policy)
+}
+
+/// Print temperature, clock and throttling state.
+pub fn print_status(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<()> {
+    let temp = temperature(v_mbox)?;
+    let max_temp = max_temperature(v_mbox)?;
+    let t = throttled(v_mbox)?;
+    let hz = clocks::rate(v_mbox, Clock::Arm)?;
+
+    println!(
+        "[i] Thermal: {}.{} C (max {}.{} C), ARM at {} MHz, policy {}",
+        temp / 1000,
+        temp % 1000 / 100,
+        max_temp / 1000,
+        max_temp % 1000 / 100,
+        hz / 1_000_000,
+        policy().name()
+    );
+
+    for (bit, name) in Throttled::CONDITIONS.iter() {
+        if t.now() & bit != 0 {
+            println!("      {} now", name);
+        } else if t.since_boot() & bit != 0 {
+            println!("      {} since boot", name);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Time keeping and periodic callbacks, based on the system timer.
+//!
+//! Callbacks run in interrupt context with IRQs masked. They must be short.
+
+use crate::devices::hw;
+use crate::{interrupt, memory::map, sync::IrqSafeNullLock};
+
+static TIMER: hw::SystemTimer = hw::SystemTimer::new(map::physical::SYS_TIMER_BASE);
+
+const NUM_PERIODIC: usize = 8;
+
+#[derive(Copy, Clone)]
+struct Periodic {
+    period_us: u64,
+    next_us: u64,
+    func: fn(usize),
+    context: usize,
+}
+
+static PERIODIC: IrqSafeNullLock<[Option<Periodic>; NUM_PERIODIC]> =
+    IrqSafeNullLock::new([None; NUM_PERIODIC]);
+
+/// Microseconds since reset.
+pub fn uptime_us() -> u64 {
+    TIMER.now()
+}
+
+/// Program the compare register for the earliest pending callback.
+fn arm(periodic: &[Option<Periodic>; NUM_PERIODIC]) {
+    if let Some(next) = periodic.iter().filter_map(|p| p.map(|p| p.next_us)).min() {
+        // Never program a time that has already passed, or the match would
+        // only come after the counter wrapped.
+        let next = next.max(uptime_us() + 10);
+
+        TIMER.set_compare_c1(next as u32);
+    }
+}
+
+fn irq_handler(_: usize) {
+    TIMER.ack_c1();
+
+    let now = uptime_us();
+    for i in 0..NUM_PERIODIC {
+        let due = PERIODIC.lock(|p| match p[i].as_mut() {
+            Some(entry) if entry.next_us <= now => {
+                entry.next_us += entry.period_us;
+                Some((entry.func, entry.context))
+            }
+            _ => None,
+        });
+
+        if let Some((func, context)) = due {
+            func(context);
+        }
+    }
+
+    PERIODIC.lock(|p| arm(p));
+}
+
+/// Install the timer interrupt handler.
+pub fn init() -> Result<(), &'static str> {
+    interrupt::register(hw::SystemTimer::IRQ_C1, irq_handler, 0)
+}
+
+/// Call `func(context)` every `period_us` microseconds. Returns an ID for
+/// `remove_periodic()`.
+pub fn add_periodic(
+    period_us: u64,
+    func: fn(usize),
+    context: usize,
+) -> Result<usize, &'static str> {
+    PERIODIC.lock(|p| {
+        let id = p
+            .iter()
+            .position(|e| e.is_none())
+            .ok_or("No free periodic timer slot.")?;
+
+        p[id] = Some(Periodic {
+            period_us,
+            next_us: uptime_us() + period_us,
+            func,
+            context,
+        });
+        arm(p);
+
+        Ok(id)
+    })
+}
+
+/// Stop a callback installed with `add_periodic()`.
+pub fn remove_periodic(id: usize) {
+    PERIODIC.lock(|p| {
+        if id < NUM_PERIODIC {
+            p[id] = None;
+        }
+    });
+}